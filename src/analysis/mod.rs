@@ -0,0 +1,563 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use serde::Serialize;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::multichannel;
+use crate::note;
+use crate::usecode;
+
+/// Minimum, maximum, average, median, percentiles and standard
+/// deviation of a set of values. Call done when all values are added.
+/// Values are signed, so finetune can be counted from -8 to 7.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+	pub min: i32,
+	pub max: i32,
+	pub sum: i64,
+	pub avg: i64,
+	pub num_values: u32,
+	pub mean: f64,
+	pub median: f64,
+	/// 10th percentile
+	pub p10: f64,
+	/// 90th percentile
+	pub p90: f64,
+	pub std_dev: f64,
+	/// All values, sorted when done
+	#[serde(skip)]
+	pub values: Vec<i32>,
+}
+
+impl Default for Stats {
+	fn default() -> Stats {
+		Stats::new()
+	}
+}
+
+impl Stats {
+	pub fn new() -> Stats {
+		Stats{min: i32::MAX, max: i32::MIN, sum: 0, avg: 0, num_values: 0,
+			mean: 0.0, median: 0.0, p10: 0.0, p90: 0.0, std_dev: 0.0, values: Vec::new()}
+	}
+
+	pub fn update(&mut self, val: i32) {
+		self.min = cmp::min(self.min, val);
+		self.max = cmp::max(self.max, val);
+		self.sum += val as i64;
+		self.values.push(val);
+
+		self.num_values += 1;
+	}
+
+	/// Computes the averages, everything is 0 without values
+	pub fn done(&mut self) {
+		if self.num_values == 0 {
+			self.min = 0;
+			self.max = 0;
+			return;
+		}
+
+		self.avg = self.sum / (self.num_values as i64);
+		self.mean = self.sum as f64 / self.num_values as f64;
+		self.values.sort();
+		self.median = self.percentile(50.0);
+		self.p10 = self.percentile(10.0);
+		self.p90 = self.percentile(90.0);
+
+		let mean = self.mean;
+		let variance = self.values.iter()
+			.map(|v| (*v as f64 - mean).powi(2))
+			.sum::<f64>() / self.num_values as f64;
+		self.std_dev = variance.sqrt();
+	}
+
+	/// The value below which percent of the values fall, interpolating
+	/// between the closest values and rounded to 3 decimals.
+	/// Only valid after done.
+	pub fn percentile(&self, percent: f64) -> f64 {
+		if self.values.is_empty() {
+			return 0.0;
+		}
+		let rank = percent.clamp(0.0, 100.0) / 100.0 * (self.values.len() - 1) as f64;
+		let low = rank.floor() as usize;
+		let high = rank.ceil() as usize;
+		let fraction = rank - low as f64;
+
+		let value = self.values[low] as f64 + (self.values[high] as f64 - self.values[low] as f64) * fraction;
+		(value * 1000.0).round() / 1000.0
+	}
+
+	/// Counts the values in num_buckets equally wide buckets from min to max.
+	/// Returns the first value, last value and count of each bucket.
+	pub fn histogram(&self, num_buckets: usize) -> Vec<(i32, i32, u32)> {
+		if self.num_values == 0 || num_buckets == 0 {
+			return Vec::new();
+		}
+		let range = (self.max as i64 - self.min as i64) + 1;
+		let num_buckets = cmp::min(num_buckets as i64, range);
+		let width = (range + num_buckets - 1) / num_buckets;
+
+		let mut buckets: Vec<(i32, i32, u32)> = (0..num_buckets)
+			.map(|i| {
+				let first = self.min as i64 + i * width;
+				let last = cmp::min(first + width - 1, self.max as i64);
+				(first as i32, last as i32, 0)
+			})
+			.filter(|(first, _, _)| *first <= self.max)
+			.collect();
+		for v in self.values.iter() {
+			let index = ((*v as i64 - self.min as i64) / width) as usize;
+			buckets[index].2 += 1;
+		}
+
+		buckets
+	}
+
+	/// Writes the histogram with bars of # scaled to width characters
+	pub fn write_histogram(&self, writer: &mut dyn Write, num_buckets: usize, width: usize) -> io::Result<()> {
+		let buckets = self.histogram(num_buckets);
+		let largest = buckets.iter().map(|b| b.2).max().unwrap_or(0);
+		for (first, last, count) in buckets {
+			let bar = if largest > 0 {
+				(count as usize * width).div_ceil(largest as usize)
+			} else {
+				0
+			};
+			writeln!(writer, "\t\t{:>7}-{:<7} {:>6} {}", first, last, count, "#".repeat(bar))?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Song summary
+#[derive(Debug, Serialize)]
+pub struct Summary {
+	pub name: String,
+	pub length: u8,
+	/// Number of samples with length > 0
+	pub num_samples: usize,
+	pub num_patterns: usize,
+	pub num_channels: usize,
+}
+
+/// Statistics for the samples with length > 0, lengths in bytes and
+/// finetune from -8 to 7
+#[derive(Debug, Serialize)]
+pub struct SampleStats {
+	pub length: Stats,
+	pub finetune: Stats,
+	pub volume: Stats,
+	pub repeat_start: Stats,
+	pub repeat_length: Stats,
+	/// Sample numbers 1-31 never used in a pattern
+	pub unused: Vec<u8>,
+	pub metrics: Vec<SampleMetrics>,
+}
+
+/// Samples within this distance from 0 are silent
+pub const SILENCE_LEVEL: u8 = 1;
+
+/// Audio metrics for the data of a sample, values are signed 8-bit
+#[derive(Debug, Serialize)]
+pub struct SampleMetrics {
+	/// Sample number 1-31
+	pub number: usize,
+	/// Largest distance from 0, 0-128
+	pub peak: u8,
+	pub rms: f64,
+	/// Average value
+	pub dc_offset: f64,
+	/// Number of values at -128 or 127
+	pub clipped: usize,
+	/// Number of bytes at the end within SILENCE_LEVEL from 0
+	pub trailing_silence: usize,
+}
+
+/// A sample number above 31 in a pattern
+#[derive(Debug, Serialize)]
+pub struct InvalidSample {
+	pub pattern: usize,
+	pub row: usize,
+	pub channel: usize,
+	pub sample_number: u8,
+}
+
+/// A period used in the patterns and how many times it is used
+#[derive(Debug, Serialize)]
+pub struct PeriodCount {
+	pub period: u16,
+	pub count: u32,
+	/// The nearest note in PERIODS, prefixed with ~ if the period differs.
+	/// Empty if no note is found.
+	pub note: String,
+}
+
+/// Sample header, lengths in bytes
+#[derive(Debug, Serialize)]
+pub struct SampleDetails {
+	/// Sample number 1-31
+	pub number: usize,
+	pub name: String,
+	pub length: u32,
+	pub finetune: u8,
+	pub volume: u8,
+	pub repeat_start: u32,
+	pub repeat_length: u32,
+}
+
+/// Pattern info
+#[derive(Debug, Serialize)]
+pub struct PatternInfo {
+	pub play_order: Vec<u8>,
+	pub unused: Vec<u8>,
+	pub empty: Vec<usize>,
+	pub periods: Vec<PeriodCount>,
+	/// Names from ptmf::EFFECT_NAMES
+	pub effects: Vec<String>,
+	pub usecode: u32,
+}
+
+pub fn summary(module: &ptmf::PTModule) -> Summary {
+	Summary {
+		name: module.name.clone(),
+		length: module.length,
+		num_samples: module.sample_info.iter().filter(|si| si.length > 0).count(),
+		num_patterns: module.patterns.len(),
+		num_channels: multichannel::num_channels(module),
+	}
+}
+
+pub fn sample_details(module: &ptmf::PTModule) -> Vec<SampleDetails> {
+	module.sample_info.iter().enumerate()
+		.map(|(i, sample)| SampleDetails {
+			number: i + 1,
+			name: sample.name.clone(),
+			length: sample.length as u32 * 2,
+			finetune: sample.finetune,
+			volume: sample.volume,
+			repeat_start: sample.repeat_start as u32 * 2,
+			repeat_length: sample.repeat_length as u32 * 2,
+		})
+		.collect()
+}
+
+pub fn sample_stats(module: &ptmf::PTModule) -> SampleStats {
+	let used_samples = module.sample_info.iter().filter(|si| si.length > 0);
+
+	let mut len = Stats::new();
+	let mut finetune = Stats::new();
+	let mut volume = Stats::new();
+	let mut repeat_start = Stats::new();
+	let mut repeat_length = Stats::new();
+
+	for sample in used_samples {
+		len.update(sample.length as i32 * 2);
+		finetune.update(note::finetune_to_signed(sample.finetune) as i32);
+		volume.update(sample.volume as i32);
+		repeat_start.update(sample.repeat_start as i32 * 2);
+		repeat_length.update(sample.repeat_length as i32 * 2);
+	}
+
+	len.done();
+	finetune.done();
+	volume.done();
+	repeat_start.done();
+	repeat_length.done();
+
+	SampleStats {
+		length: len,
+		finetune,
+		volume,
+		repeat_start,
+		repeat_length,
+		unused: find_unused_samples(module),
+		metrics: module.sample_info.iter().enumerate()
+			.filter(|(_, si)| si.length > 0)
+			.map(|(i, si)| sample_metrics(i + 1, &si.data))
+			.collect(),
+	}
+}
+
+/// Number of bytes at the end of data within level from 0
+pub fn trailing_silence(data: &[u8], level: u8) -> usize {
+	data.iter().rev()
+		.take_while(|v| (**v as i8 as i16).abs() <= level as i16)
+		.count()
+}
+
+pub fn sample_metrics(number: usize, data: &[u8]) -> SampleMetrics {
+	let mut peak = 0;
+	let mut sum = 0i64;
+	let mut sum_squares = 0u64;
+	let mut clipped = 0;
+	for v in data.iter() {
+		let v = *v as i8 as i64;
+		peak = cmp::max(peak, v.abs());
+		sum += v;
+		sum_squares += (v * v) as u64;
+		if v == -128 || v == 127 {
+			clipped += 1;
+		}
+	}
+
+	let (rms, dc_offset) = if data.is_empty() {
+		(0.0, 0.0)
+	} else {
+		let n = data.len() as f64;
+		((sum_squares as f64 / n).sqrt(), sum as f64 / n)
+	};
+
+	SampleMetrics {
+		number,
+		peak: peak as u8,
+		rms,
+		dc_offset,
+		clipped,
+		trailing_silence: trailing_silence(data, SILENCE_LEVEL),
+	}
+}
+
+pub fn pattern_info(module: &ptmf::PTModule, use_spn: bool) -> PatternInfo {
+	PatternInfo {
+		play_order: module.positions.data[0..module.length as usize].to_vec(),
+		unused: find_unused_patterns(module),
+		empty: find_empty_patterns(module),
+		periods: period_histogram(module, use_spn),
+		effects: used_effects(module).iter()
+			.map(|i| ptmf::EFFECT_NAMES[*i].to_string())
+			.collect(),
+		usecode: usecode::usecode(module),
+	}
+}
+
+/// Patterns that are not in the play order
+pub fn find_unused_patterns(module: &ptmf::PTModule) -> Vec<u8> {
+	let mut unused:Vec<u8> = Vec::new();
+	let positions = &module.positions.data[0..module.length as usize];
+	let num_patterns = module.patterns.len();
+	for i in 0..num_patterns as u8 {
+		if !positions.contains(&i) {
+			unused.push(i);
+		}
+	}
+
+	unused
+}
+
+/// Patterns without notes, sample numbers and effects
+pub fn find_empty_patterns(module: &ptmf::PTModule) -> Vec<usize> {
+	let mut empty_patterns = Vec::new();
+	for i in 0..module.patterns.len() {
+		let empty = module.patterns[i].rows.iter()
+			.flat_map(|row| row.channels.iter())
+			.all(|channel| channel.period == 0 &&
+				channel.sample_number == 0 &&
+				channel.effect == 0);
+		if empty {
+			empty_patterns.push(i);
+		}
+	}
+
+	empty_patterns
+}
+
+/// Sample numbers 1-31 that are never used in a pattern
+pub fn find_unused_samples(module: &ptmf::PTModule) -> Vec<u8> {
+	let mut unused:Vec<u8> = Vec::new();
+	let mut used = [0u8;32];
+
+	// Find all used samples
+	for pattern in &module.patterns {
+		for row in &pattern.rows {
+			for channel in &row.channels {
+				let number = channel.sample_number as usize;
+				if number > 0 && number <= 31 {
+					used[number] = 1;
+				}
+			}
+		}
+	}
+
+	// Find all unused samples
+	for (i, used) in used.iter().enumerate().skip(1).take(module.sample_info.len()) {
+		if *used == 0 {
+			unused.push(i as u8);
+		}
+	}
+
+	unused
+}
+
+/// Sample numbers in the patterns that are above 31
+pub fn find_invalid_samples(module: &ptmf::PTModule) -> Vec<InvalidSample> {
+	let mut invalid = Vec::new();
+	for pattern_no in 0..module.patterns.len() {
+		let pattern = &module.patterns[pattern_no];
+		for row_no in 0..pattern.rows.len() {
+			let row = &pattern.rows[row_no];
+			for channel_no in 0..row.channels.len() {
+				let number = row.channels[channel_no].sample_number;
+				if number > 31 {
+					invalid.push(InvalidSample {
+						pattern: pattern_no,
+						row: row_no,
+						channel: channel_no,
+						sample_number: number,
+					});
+				}
+			}
+		}
+	}
+
+	invalid
+}
+
+/// All periods used in the patterns with the number of times they are used,
+/// ordered by period
+pub fn period_histogram(module: &ptmf::PTModule, use_spn: bool) -> Vec<PeriodCount> {
+	let mut map = BTreeMap::<u16,u32>::new();
+	for pattern in &module.patterns {
+		for row in &pattern.rows {
+			for channel in &row.channels {
+				if channel.period > 0 {
+					let count = map.entry(channel.period).or_insert(0);
+					*count += 1;
+				}
+			}
+		}
+	}
+
+	map.into_iter()
+		.map(|(period, count)| {
+			let note = match note::find_nearest_note(period) {
+				Some((index, 0)) => note::note_name(index, use_spn),
+				Some((index, _)) => format!("~{}", note::note_name(index, use_spn)),
+				None => String::new(),
+			};
+			PeriodCount{period, count, note}
+		})
+		.collect()
+}
+
+/// Returns the effect index in ptmf::EFFECT_NAMES for an effect command,
+/// or None for 000 which is not really an effect
+pub fn effect_index(effect: u16) -> Option<usize> {
+	let mut index = (effect & 0x0f00) >> 8;
+	if index == 0 && effect & 0x00ff == 0 {
+		return None;
+	}
+	if index == 0xe {
+		index = ((effect & 0x00f0) >> 4) + 16;
+	}
+
+	Some(index as usize)
+}
+
+/// Effects used in the patterns as indices in ptmf::EFFECT_NAMES,
+/// E-commands are 16-31
+pub fn used_effects(module: &ptmf::PTModule) -> Vec<usize> {
+	let mut effects = [false; 32]; // 32 effects
+	for pattern in &module.patterns {
+		for row in &pattern.rows {
+			for channel in &row.channels {
+				if let Some(index) = effect_index(channel.effect) {
+					effects[index] = true;
+				}
+			}
+		}
+	}
+
+	(0..effects.len()).filter(|i| effects[*i]).collect()
+}
+
+/// The sections selected for a report, one report per module
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub summary: Option<Summary>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub samples: Option<Vec<SampleDetails>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub sample_stats: Option<SampleStats>,
+	/// Sample numbers in the patterns that are out of range
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub invalid_samples: Option<Vec<InvalidSample>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub pattern_info: Option<PatternInfo>,
+}
+
+/// Writes the report as one line of JSON, so the output for many
+/// files is newline delimited JSON
+pub fn write_json(writer: &mut dyn Write, report: &Report) -> io::Result<()> {
+	serde_json::to_writer(&mut *writer, report)?;
+	writeln!(writer)
+}
+
+/// Quotes a CSV field if needed
+fn csv_field(value: &str) -> String {
+	if value.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		value.to_string()
+	}
+}
+
+/// Writes the per-sample tables of the reports, the sample headers,
+/// the sample statistics and the sample metrics, separated by an
+/// empty line. Each table has one header row and the rows of all
+/// reports.
+pub fn write_csv(writer: &mut dyn Write, reports: &[Report]) -> io::Result<()> {
+	let mut first = true;
+	if reports.iter().any(|r| r.samples.is_some()) {
+		writeln!(writer, "file,number,name,length,finetune,volume,repeat_start,repeat_length")?;
+		for report in reports {
+			for s in report.samples.iter().flatten() {
+				writeln!(writer, "{},{},{},{},{},{},{},{}",
+					csv_field(&report.file), s.number, csv_field(&s.name), s.length,
+					s.finetune, s.volume, s.repeat_start, s.repeat_length)?;
+			}
+		}
+		first = false;
+	}
+
+	if reports.iter().any(|r| r.sample_stats.is_some()) {
+		if !first {
+			writeln!(writer)?;
+		}
+		writeln!(writer, "file,field,min,max,avg,count,median,p10,p90,std_dev")?;
+		for report in reports {
+			if let Some(ref stats) = report.sample_stats {
+				let fields = [
+					("length", &stats.length),
+					("finetune", &stats.finetune),
+					("volume", &stats.volume),
+					("repeat_start", &stats.repeat_start),
+					("repeat_length", &stats.repeat_length),
+				];
+				for (name, s) in fields.iter() {
+					writeln!(writer, "{},{},{},{},{},{},{:.3},{:.3},{:.3},{:.3}",
+						csv_field(&report.file), name, s.min, s.max, s.avg, s.num_values,
+						s.median, s.p10, s.p90, s.std_dev)?;
+				}
+			}
+		}
+
+		writeln!(writer)?;
+		writeln!(writer, "file,number,peak,rms,dc_offset,clipped,trailing_silence")?;
+		for report in reports {
+			for m in report.sample_stats.iter().flat_map(|stats| stats.metrics.iter()) {
+				writeln!(writer, "{},{},{},{:.3},{:.3},{},{}",
+					csv_field(&report.file), m.number, m.peak, m.rms, m.dc_offset,
+					m.clipped, m.trailing_silence)?;
+			}
+		}
+	}
+
+	Ok(())
+}
@@ -9,10 +9,13 @@ use serde::Deserialize;
 
 // ProTracker and ThePlayer
 use modfile::ptmf;
+// Input format detection and multichannel modules
+use modtool::loader;
+use modtool::multichannel;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 json2mod.
 
 Usage: 
@@ -46,22 +49,26 @@ fn main() -> Result<()> {
 	}
 
 	// Open json file
-	let ref first_filename = args.arg_source;
+	let first_filename = &args.arg_source;
 	let file = File::open(first_filename)
 				.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
 	
-	let reader = BufReader::new(&file);
-	let mut module: ptmf::PTModule = serde_json::from_reader(reader)
+	// The format is detected, any supported input works
+	let mut reader = BufReader::new(&file);
+	let (mut module, warnings): (ptmf::PTModule, Vec<String>) = loader::read_with_warnings(&mut reader, false)
 					.with_context(|| format!("Failed to parse file: '{}'", first_filename))?;
+	for warning in warnings {
+		eprintln!("Warning: {}", warning);
+	}
 
 
-	let ref filename = args.arg_destination;
-	let file = File::create(&filename)
+	let filename = &args.arg_destination;
+	let file = File::create(filename)
 				.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 
 	let mut writer = BufWriter::new(&file);		
-	match ptmf::write_mod(&mut writer,&mut module) {
+	match multichannel::write_mod(&mut writer, &mut module) {
 		Ok(_) => (),
 		Err(e) => {
 			return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
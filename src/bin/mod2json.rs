@@ -12,10 +12,12 @@ use serde::{Serialize, Deserialize};
 use modfile::ptmf;
 // Pretty printing of JSON
 use modtool::pretty::PrettyFormatter2;
+// Input format detection
+use modtool::loader;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 mod2json.
 
 Usage: 
@@ -26,7 +28,7 @@ Usage:
 Options:
     -V, --version          Show version info.
     -h, --help             Show this text.
-    --in-p61               Input file format is The Player 6.1A.
+    --in-p61               Input file format is The Player 6.1A, if not detected.
     --skip-filesize-check  Skip check if all data has been parsed.
 
     <source>               Input file.
@@ -52,20 +54,29 @@ fn main() -> Result<()> {
 		return Ok(());
 	}
 	
-	fn mod_fn_true(reader: &mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> {
-		return ptmf::read_mod(reader, true);
+	// The format is detected, --in-p61 is only needed if detection fails
+	fn mod_fn_true(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+		let (module, warnings) = loader::read_with_warnings(reader, true)?;
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		Ok(module)
 	}
 
-	fn mod_fn_false(reader: &mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> {
-		return ptmf::read_mod(reader, false);
+	fn mod_fn_false(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+		let (module, warnings) = loader::read_with_warnings(reader, false)?;
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		Ok(module)
 	}
 
 	let skip_file_size_check = args.flag_skip_filesize_check;
 
 	let p61 = args.flag_in_p61;
-	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> = 
+	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule> = 
 		if p61 {
-			ptmf::read_p61
+			loader::read_p61
 		} else {
 			if skip_file_size_check {
 				mod_fn_true
@@ -75,7 +86,7 @@ fn main() -> Result<()> {
 		};
 		
 	// Open the module
-	let ref first_filename = args.arg_source;
+	let first_filename = &args.arg_source;
 	let file = File::open(first_filename)
 		.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
 	
@@ -90,8 +101,8 @@ fn main() -> Result<()> {
 	// Close file
 	drop(file);
 
-	let ref filename = args.arg_destination;
-	let file = File::create(&filename)
+	let filename = &args.arg_destination;
+	let file = File::create(filename)
 		.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 	let writer = BufWriter::new(&file);
@@ -13,10 +13,14 @@ use serde::Deserialize;
 
 // ProTracker and ThePlayer
 use modfile::ptmf;
+// Input format detection
+use modtool::loader;
+// The Player usecode
+use modtool::usecode;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 mod2p61.
 
 Usage: 
@@ -53,17 +57,26 @@ fn main() -> Result<()> {
 		return Ok(());
 	}
 	
-	fn mod_fn_true(reader: &mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> {
-		return ptmf::read_mod(reader, true);
+	// The format is detected
+	fn mod_fn_true(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+		let (module, warnings) = loader::read_with_warnings(reader, true)?;
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		Ok(module)
 	}
 
-	fn mod_fn_false(reader: &mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> {
-		return ptmf::read_mod(reader, false);
+	fn mod_fn_false(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+		let (module, warnings) = loader::read_with_warnings(reader, false)?;
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		Ok(module)
 	}
 
 	let skip_file_size_check = args.flag_skip_filesize_check;
 
-	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> = 
+	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule> = 
 		if skip_file_size_check {
 			mod_fn_true
 		} else {
@@ -71,7 +84,7 @@ fn main() -> Result<()> {
 		};
 		
 	// Open the module
-	let ref first_filename = args.arg_source;
+	let first_filename = &args.arg_source;
 	let file = File::open(first_filename)
 		.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
 	
@@ -119,17 +132,17 @@ fn main() -> Result<()> {
 		}
 	};
 
-	let usecode = get_usecode(&p61module);
+	let usecode = usecode::usecode(&p61module);
 
-	let ref filename = args.arg_destination;
-	let file = File::create(&filename)
+	let filename = &args.arg_destination;
+	let file = File::create(filename)
 		.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 	let mut writer = BufWriter::new(&file);
 	writer.write_all(&p61data)
 		.with_context(|| format!("Failed to write module {}", filename))?;
 
-	if args.flag_sample_file.len() <= 0 
+	if args.flag_sample_file.is_empty()
 	{
 		// One file for all data
 		writer.write_all(&p61samples)
@@ -138,8 +151,8 @@ fn main() -> Result<()> {
 	else
 	{
 		// Separate file for samples
-		let ref filename = args.flag_sample_file;
-		let file = File::create(&filename)
+		let filename = &args.flag_sample_file;
+		let file = File::create(filename)
 			.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 		let mut writer = BufWriter::new(&file);
@@ -150,53 +163,3 @@ fn main() -> Result<()> {
 	println!("Usecode: ${:08x}",usecode);
 	Ok(())
 }
-
-/// Gets The Player usecode
-fn get_usecode(module: &ptmf::PTModule) -> u32 {
-
-	let mut effects = [false; 32]; // 32 effects
-	for pattern_no in 0..module.patterns.len() {
-		let ref pattern = module.patterns[pattern_no];
-		for row_no in 0..pattern.rows.len() {
-			let ref row = pattern.rows[row_no];
-			for channel_no in 0..row.channels.len() {
-				let ref channel = row.channels[channel_no];
-				let mut effect = (channel.effect & 0x0f00) >> 8;
-				if effect == 0 {
-					if channel.effect & 0x00ff == 0 {
-						// Not really an effect
-						continue;
-					}
-				}
-				if effect == 0xe {
-					effect = ((channel.effect & 0x00f0) >> 4) + 16;
-				}
-				effects[effect as usize] = true;
-			}
-		}
-	}
-
-	let mut usecode:u32 = 0;
-	for i in 0..effects.len() {
-		if effects[i] {
-			// Figure out the player usecode
-			// Some have special handling
-			if i == 0 {
-				// 
-				usecode |= 1 << 8; // The player converts 0 to 8
-			} else {
-				usecode |= 1 << i;
-			} 
-		}
-	}
-	
-	// Check if finetune is used
-	for si in module.sample_info.iter() {
-		if si.finetune != 0 {
-			usecode |= 1;
-			break;
-		}
-	}
-
-	usecode
-}
\ No newline at end of file
@@ -6,6 +6,7 @@ use std::io::BufReader;
 use std::io::Read;
 use std::cmp;
 use std::str::FromStr;
+use std::path::Path;
 use std::collections::BTreeMap;
 use anyhow::{Context, Result, anyhow};
 // Command line
@@ -15,42 +16,89 @@ use serde::{Deserialize};
 
 // ProTracker and ThePlayer
 use modfile::ptmf;
+// Notes, periods, WAV and IFF files
+use modtool::note;
+use modtool::wav;
+use modtool::iff;
+use modtool::sample;
+// Playback
+use modtool::sequencer;
+use modtool::reachability;
+use modtool::analysis;
+use modtool::replayer;
+use modtool::timeline;
+use modtool::text;
+use modtool::diff;
+use modtool::merge3;
+use modtool::transform;
+use modtool::multichannel;
+use modtool::loader;
+use modtool::collection;
 
 // TODO Refactor this to several files
 // TODO Move some of the functions to the modfile crate
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 modtool.
 
 Usage: 
     modtool (-h | --help)
     modtool (-V | --version)
-    modtool show [--summary] [--sample-info] [--sample-stats] [--pattern-info] [--use-spn] [--in-p61] [--skip-filesize-check] <file>...
-    modtool save (--number=<number> | --all) [--in-p61] [--skip-filesize-check] [--use-sample-name] <fileprefix> <file>...
-    modtool convert [--unused-patterns] [--unused-samples] [--in-p61] [--skip-filesize-check] <fileprefix> <file>...
+    modtool show [--format=<format>] [--summary] [--sample-info] [--sample-stats] [--pattern-info] [--patterns [--pattern=<patterns>] [--position=<positions>] [--highlight-sync]] [--reachability] [--duration] [--vblank] [--use-spn] [--in-p61] [--skip-filesize-check] <file>...
+    modtool save (--number=<number> | --all) [--format=<format>] [--note=<note>] [--in-p61] [--skip-filesize-check] [--use-sample-name] <fileprefix> <file>...
+    modtool convert [--unused-patterns] [--unused-samples] [--unreachable] [--duplicate-patterns] [--duplicate-samples [--bake-volume]] [--trim-samples] [--in-p61] [--skip-filesize-check] <fileprefix> <file>...
     modtool merge [--sync] <target> <file>...
     modtool insert <target> <file>
+    modtool replace-sample --number=<number> [--note=<note>] [--resample] [--in-p61] [--skip-filesize-check] <target> <file> <samplefile>
+    modtool import-patterns [--use-spn] [--in-p61] [--skip-filesize-check] <target> <file> <textfile>
+    modtool diff [--json] [--use-spn] [--in-p61] [--skip-filesize-check] <file> <other>
+    modtool merge3 [--conflicts=<conflicts>] [--in-p61] [--skip-filesize-check] <base> <ours> <theirs> <target>
+    modtool transpose --semitones=<semitones> [--pattern=<patterns>] [--channel=<channels>] [--sample=<samples>] [--clamp] [--in-p61] [--skip-filesize-check] <target> <file>
+    modtool channels [--copy=<channels> --from=<source>] [--swap=<channels>] [--order=<channels>] [--clear=<channels> [--keep-effects]] [--pattern=<patterns>] [--in-p61] [--skip-filesize-check] <target> <file>
+    modtool render [--rate=<rate>] [--vblank] [--seconds=<seconds>] [--stems] [--sample-stems] [--in-p61] [--skip-filesize-check] <target> <file>
+    modtool timeline [--vblank] [--in-p61] [--skip-filesize-check] <target> <file>
+    modtool resample --number=<number> --factor=<factor> [--zero-start] [--in-p61] [--skip-filesize-check] <target> <file>
+    modtool collection [--format=<format>] [--skip-filesize-check] <target> <path>...
 
 Options:
     -V, --version         Show version info.
     -h, --help            Show this text.
 
     show                  Show various info and statistics.
+      --format=<format>   Output format, text, json or csv. json writes one
+                          line per file with the summary, sample info,
+                          sample stats and pattern info. csv writes the
+                          sample info and sample stats tables for all
+                          files. The default is text.
       --summary           Show summary info.
       --sample-info       Show info about samples.
       --sample-stats      Show sample statistics.
       --pattern-info      Show info about patterns.
+      --patterns          Show pattern data, one row per line like C-2 01 C40.
+                          Periods that are not notes are shown as numbers.
+      --pattern=<patterns>  Only show these patterns, e.g. 0,2,5-7.
+      --position=<positions>  Show the patterns at these positions, e.g. 0-3,
+                          in play order.
+      --highlight-sync    Mark rows with E8x sync commands.
+      --reachability      Show positions, patterns and rows that are never played.
+      --duration          Show play time and where the song loops.
+      --vblank            Use vblank timing, Fxx always sets speed.
       --use-spn           Use scientific pitch notation where middle C is C4.
-      --in-p61            Input file format is The Player 6.1A.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
       --skip-filesize-check  Skip check if all data has been parsed.
       <file>              File(s) to process.
 
-    save                  Save samples, RAW 8-bit signed.
+    save                  Save samples.
       --all               Save all samples.
       --number=<number>   Save only sample <number>.
-      --in-p61            Input file format is The Player 6.1A.
+      --format=<format>   Sample file format, raw (8-bit signed),
+                          wav (8-bit unsigned with loop) or
+                          8svx (IFF 8SVX with loop). The default is raw.
+      --note=<note>       Note or period played at the WAV/8SVX sample rate,
+                          e.g. C-2 or 428 [default: C-2].
+      --in-p61            Input file format is The Player 6.1A, if not detected.
       --skip-filesize-check  Skip check if all data has been parsed.
       --use-sample-name   Use sample name as filename, if valid.
       <fileprefix>        Use <fileprefix> as prefix to filenames when saving.
@@ -59,9 +107,20 @@ Options:
     convert               Remove unused/samples and or patterns. 
                           Can also convert from The Player to ProTracker.
                           Including 8-bit and 4-bit delta packed samples.
+                          Modules with 6, 8 or more channels, like 6CHN, 8CHN,
+                          xxCH, FLT8 and CD81, are written as xCHN or xxCH.
+                          15-sample Soundtracker modules are written as M.K.
       --unused-patterns   Remove unused patterns.
       --unused-samples    Remove unused samples. 
-      --in-p61            Input file format is The Player 6.1A.
+      --unreachable       Remove positions and patterns that are never played,
+                          following Bxx and Dxx, and clear rows never played.
+      --duplicate-patterns  Remove identical patterns, keeping the first copy.
+      --duplicate-samples  Use the first of identical samples and clear the others.
+      --bake-volume       Samples that only differ in volume are also duplicates,
+                          the volume is set with Cxx instead.
+      --trim-samples      Remove sample data after the loop end of looped
+                          samples and the silent end of one-shot samples.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
       --skip-filesize-check  Skip check if all data has been parsed.
       <fileprefix>        Use <fileprefix> as prefix to filenames when saving.
       <file>              File(s) to process.
@@ -75,6 +134,125 @@ Options:
                           unless the pattern already has at least one E8x command.
       <target>            Output file.
       <file>              File(s) to process.
+
+    replace-sample        Replace a sample with a WAV, 8SVX or RAW 8-bit signed file.
+                          The format is detected from the file header.
+                          WAV files are converted to 8-bit mono.
+                          Loop points are imported from WAV and 8SVX.
+      --number=<number>   Sample number to replace.
+      --note=<note>       Note or period used with --resample [default: C-2].
+      --resample          Resample so the sample keeps its pitch when played at <note>.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file.
+      <file>              File to process.
+      <samplefile>        Sample file to import.
+
+    import-patterns       Replace patterns with patterns in text, as written by
+                          show --patterns. Each pattern starts with a Pattern <n> line,
+                          rows not in the text are cleared. Notes can also be
+                          periods, like 430.
+      --use-spn           Use scientific pitch notation where middle C is C4.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file.
+      <file>              File to process.
+      <textfile>          Text file with patterns.
+
+    diff                  Show what differs between two modules, song name, length,
+                          positions, sample headers and data, and pattern cells.
+      --json              Write the differences as JSON.
+      --use-spn           Use scientific pitch notation where middle C is C4.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <file>              Old file.
+      <other>             New file.
+
+    merge3                Three-way merge of two revisions of a module. Changes from
+                          <base> to <theirs> are merged into <ours> cell by cell,
+                          also song name, length, positions and sample headers and data.
+                          Values changed differently on both sides are conflicts,
+                          they keep the value from <ours> and are reported as JSON.
+                          The merged module is always written, the exit status
+                          is non-zero if there are conflicts.
+      --conflicts=<conflicts>  Write conflicts to this file instead of stdout.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <base>              Common ancestor.
+      <ours>              Our revision.
+      <theirs>            Their revision.
+      <target>            Output file.
+
+    transpose             Move notes up or down by semitones. Notes that would end up
+                          outside C-1 to B-3 are reported and nothing is written.
+      --semitones=<semitones>  Number of semitones, negative is down.
+      --pattern=<patterns>  Only these patterns, e.g. 0,2,5-7.
+      --channel=<channels>  Only these channels, e.g. 1,4.
+      --sample=<samples>  Only notes played with these samples, e.g. 1-3.
+      --clamp             Clamp notes outside the range to C-1 or B-3 and write anyway.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file.
+      <file>              File to process.
+
+    channels              Rearrange channels. Operations are done in the order
+                          copy, swap, order and clear. Channels start at 1.
+      --copy=<channels>   Copy a channel from <source>, e.g. 2,3 copies channel 2
+                          in <source> to channel 3.
+      --from=<source>     Module to copy from.
+      --swap=<channels>   Swap two channels, e.g. 1,4.
+      --order=<channels>  New channel order, e.g. 2,1,4,3.
+      --clear=<channels>  Clear notes and effects in these channels, e.g. 3,4.
+      --keep-effects      Only clear notes and sample numbers.
+      --pattern=<patterns>  Only these patterns, e.g. 0,2,5-7.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file.
+      <file>              File to process.
+
+    render                Play the module like ProTracker and write a 16-bit stereo WAV.
+                          Stops when the song ends or loops.
+      --rate=<rate>       Sample rate [default: 44100].
+      --vblank            Use vblank timing, Fxx always sets speed.
+      --seconds=<seconds>  Stop after <seconds> seconds.
+      --stems             Also write one mono WAV per channel, <target>_ch<n>.wav.
+      --sample-stems      Also write one stereo WAV per used sample, <target>_s<n>.wav.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file.
+      <file>              File to process.
+
+    timeline              Export all E8x commands in playback order with position,
+                          pattern, row, channel (from 1), vblank frame and
+                          milliseconds.
+      --vblank            Use vblank timing, Fxx always sets speed.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file, the format is given by the extension,
+                          .json, .csv, .h (C header) or .s/.i/.asm (68k dc.l/dc.w).
+      <file>              File to process.
+
+    resample              Resample a sample with a low-pass filter and transpose
+                          all notes using it to keep the pitch. 9xx sample
+                          offsets and portamento speeds are scaled.
+      --number=<number>   Sample number to resample.
+      --factor=<factor>   New length divided by old length, a semitone ratio,
+                          e.g. 0.5 halves the sample rate and moves the notes
+                          an octave down, 1.0595 moves them a semitone up.
+      --zero-start        Set the first two bytes of a sample without a loop
+                          to zero, ProTracker plays them after the end.
+      --in-p61            Input file format is The Player 6.1A, if not detected.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file.
+      <file>              File to process.
+
+    collection            Statistics for a whole collection of modules: formats,
+                          channel counts, effect usage, sample lengths and
+                          files that failed to load, with the reason.
+      --format=<format>   Output format, text or json. The default is text.
+      --skip-filesize-check  Skip check if all data has been parsed.
+      <target>            Output file.
+      <path>              Files or directories, searched recursively.
 ";
 
 #[derive(Debug, Deserialize)]
@@ -91,279 +269,399 @@ struct Args {
     flag_sample_info: bool,
 	flag_sample_stats: bool,
 	flag_pattern_info: bool,
+	flag_patterns: bool,
+	flag_pattern: String,
+	flag_position: String,
+	flag_highlight_sync: bool,
+	flag_reachability: bool,
+	flag_duration: bool,
 	flag_use_spn: bool,
 	
 	cmd_save: bool,
 	flag_all: bool,
 	flag_number: String,
+	flag_format: String,
+	flag_note: String,
 	flag_use_sample_name: bool,
 	arg_fileprefix: String,
 	
 	cmd_convert: bool,
 	flag_unused_patterns: bool,
 	flag_unused_samples: bool,
+	flag_unreachable: bool,
+	flag_duplicate_patterns: bool,
+	flag_duplicate_samples: bool,
+	flag_trim_samples: bool,
+	flag_bake_volume: bool,
 
 	cmd_merge: bool,
 	flag_sync: bool,
 	arg_target: String,
 
 	cmd_insert: bool,
+
+	cmd_replace_sample: bool,
+	flag_resample: bool,
+	arg_samplefile: String,
+
+	cmd_import_patterns: bool,
+	arg_textfile: String,
+	arg_path: Vec<String>,
+
+	cmd_diff: bool,
+	flag_json: bool,
+	arg_other: String,
+
+	cmd_merge3: bool,
+	flag_conflicts: String,
+	arg_base: String,
+	arg_ours: String,
+	arg_theirs: String,
+
+	cmd_transpose: bool,
+	flag_semitones: String,
+	flag_factor: String,
+	flag_channel: String,
+	flag_sample: String,
+	flag_clamp: bool,
+	flag_zero_start: bool,
+
+	cmd_channels: bool,
+	flag_copy: String,
+	flag_from: String,
+	flag_swap: String,
+	flag_order: String,
+	flag_clear: String,
+	flag_keep_effects: bool,
+
+	cmd_render: bool,
+	flag_rate: String,
+	flag_vblank: bool,
+	flag_seconds: String,
+	flag_stems: bool,
+	flag_sample_stems: bool,
+
+	cmd_timeline: bool,
+	cmd_resample: bool,
+	cmd_collection: bool,
 }
 
-#[derive(Debug)]
-struct Stats {
-	pub min: u32,
-	pub max: u32,
-	pub sum: usize,
-	pub avg: usize,
-	pub num_values: u32
+#[derive(Debug, PartialEq)]
+enum SampleFormat {
+	Raw,
+	Wav,
+	Svx,
 }
 
-impl Stats {
-	pub fn new() -> Stats {
-		Stats{min: u32::max_value(), max: u32::min_value(), sum: 0, avg: 0, num_values: 0}
-	}
+impl FromStr for SampleFormat {
+	type Err = anyhow::Error;
 
-	pub fn update(&mut self, val: u32) {
-		self.min = cmp::min(self.min, val);
-		self.max = cmp::max(self.max, val);
-		self.sum += val as usize;
-		
-		self.num_values += 1;
+	fn from_str(s: &str) -> Result<SampleFormat> {
+		match s.to_lowercase().as_str() {
+			"" | "raw" => Ok(SampleFormat::Raw),
+			"wav" => Ok(SampleFormat::Wav),
+			"8svx" | "iff" => Ok(SampleFormat::Svx),
+			_ => Err(anyhow!("Invalid sample format '{}'", s)),
+		}
 	}
-	
-	pub fn done(&mut self) {
-		self.avg = self.sum / (self.num_values as usize);
+}
+
+impl SampleFormat {
+	fn extension(&self) -> &'static str {
+		match self {
+			SampleFormat::Raw => "raw",
+			SampleFormat::Wav => "wav",
+			SampleFormat::Svx => "8svx",
+		}
 	}
 }
 
 fn show_summary(module: &ptmf::PTModule) {
+	let summary = analysis::summary(module);
 	println!("Song summary");
-	println!("\tSongname: {}", module.name);
-	println!("\tLength: {}", module.length);
-	let used_samples = module.sample_info.iter().filter(|si| si.length > 0);
-	println!("\tNumber of samples with length > 0: {}", used_samples.count());
-	println!("\tNumber of patterns: {}", module.patterns.len());
-	println!("\tNumber of channels: {}", module.patterns[0].rows[0].channels.len());
-	println!("");
+	println!("\tSongname: {}", summary.name);
+	println!("\tLength: {}", summary.length);
+	println!("\tNumber of samples with length > 0: {}", summary.num_samples);
+	println!("\tNumber of patterns: {}", summary.num_patterns);
+	println!("\tNumber of channels: {}", summary.num_channels);
+	println!();
+}
+
+/// Parses a list of numbers and ranges like 0,2,5-7
+fn parse_number_list(list: &str) -> Result<Vec<usize>> {
+	let mut result = Vec::new();
+	for part in list.split(',') {
+		let part = part.trim();
+		let (first, last) = match part.find('-') {
+			Some(i) => (&part[..i], &part[i + 1..]),
+			None => (part, part),
+		};
+		let first = usize::from_str(first.trim())
+			.with_context(|| format!("Invalid number '{}'", part))?;
+		let last = usize::from_str(last.trim())
+			.with_context(|| format!("Invalid number '{}'", part))?;
+		result.extend(first..=last);
+	}
+
+	Ok(result)
+}
+
+/// Selection of patterns, channels 1-4 and samples 1-31 from lists like 0,2,5-7
+fn parse_selection(patterns: &str, channels: &str, samples: &str) -> Result<transform::Selection> {
+	let mut selection = transform::Selection::default();
+	if !patterns.is_empty() {
+		selection.patterns = parse_number_list(patterns)?;
+	}
+	if !channels.is_empty() {
+		for channel in parse_number_list(channels)? {
+			if channel < 1 {
+				return Err(anyhow!("Invalid channel '{}'", channel));
+			}
+			selection.channels.push(channel - 1);
+		}
+	}
+	if !samples.is_empty() {
+		for number in parse_number_list(samples)? {
+			if !(1..=31).contains(&number) {
+				return Err(anyhow!("Invalid sample number '{}'", number));
+			}
+			selection.samples.push(number as u8);
+		}
+	}
+
+	Ok(selection)
+}
+
+/// Parses a list of channels starting at 1, like 2,1,4,3, to channels starting at 0
+fn parse_channel_list(list: &str, count: Option<usize>) -> Result<Vec<usize>> {
+	let channels = parse_number_list(list)?;
+	if let Some(count) = count {
+		if channels.len() != count {
+			return Err(anyhow!("Expected {} channels in '{}'", count, list));
+		}
+	}
+	let mut result = Vec::new();
+	for channel in channels {
+		if channel < 1 {
+			return Err(anyhow!("Invalid channel '{}'", channel));
+		}
+		result.push(channel - 1);
+	}
+
+	Ok(result)
+}
+
+fn show_patterns(module: &ptmf::PTModule, patterns: &str, positions: &str, use_spn: bool, highlight_sync: bool) -> Result<()> {
+	// Pattern number and header for each block
+	let mut blocks: Vec<(usize, String)> = Vec::new();
+	if !positions.is_empty() {
+		for pos in parse_number_list(positions)? {
+			if pos >= module.length as usize {
+				return Err(anyhow!("Invalid position '{}'", pos));
+			}
+			let pattern = module.positions.data[pos] as usize;
+			blocks.push((pattern, format!("Position {} Pattern {}", pos, pattern)));
+		}
+	} else if !patterns.is_empty() {
+		for pattern in parse_number_list(patterns)? {
+			blocks.push((pattern, format!("Pattern {}", pattern)));
+		}
+	} else {
+		for pattern in 0..module.patterns.len() {
+			blocks.push((pattern, format!("Pattern {}", pattern)));
+		}
+	}
+
+	let stdout = std::io::stdout();
+	let mut writer = stdout.lock();
+	for (pattern, header) in blocks {
+		if pattern >= module.patterns.len() {
+			return Err(anyhow!("Invalid pattern '{}'", pattern));
+		}
+		text::write_pattern(&mut writer, &header, &module.patterns[pattern], use_spn, highlight_sync)?;
+	}
+
+	Ok(())
+}
+
+fn show_reachability(module: &ptmf::PTModule) {
+	let reachability = reachability::analyze(module);
+
+	println!("Reachability");
+	if reachability.aborted {
+		println!("\tSong neither stops nor loops, result is incomplete");
+	}
+	print!("\tUnreachable positions: ");
+	for pos in reachability.unreachable_positions() {
+		print!("{} ", pos);
+	}
+	println!();
+
+	print!("\tUnreachable patterns: ");
+	for pattern in reachability.unreachable_patterns() {
+		print!("{} ", pattern);
+	}
+	println!();
+
+	println!("\tDead rows: ");
+	for pattern in 0..module.patterns.len() {
+		let dead = reachability.dead_rows(pattern);
+		if dead.is_empty() {
+			continue;
+		}
+		print!("\t Pattern {}: ", pattern);
+		for (first, last) in dead {
+			if first == last {
+				print!("{} ", first);
+			} else {
+				print!("{}-{} ", first, last);
+			}
+		}
+		println!();
+	}
+	println!();
+}
+
+fn show_duration(module: &ptmf::PTModule, cia: bool) {
+	let duration = sequencer::song_duration(module, cia);
+	let minutes = (duration.seconds / 60.0) as u32;
+
+	println!("Song duration");
+	println!("\tTiming: {}", if cia { "CIA" } else { "vblank" });
+	println!("\tPlay time: {:.3}s ({}:{:06.3})", duration.seconds, minutes, duration.seconds - minutes as f64 * 60.0);
+	println!("\tVblank frames: {}", duration.vblank_frames());
+	println!("\tTicks: {}", duration.ticks);
+	println!("\tRows: {}", duration.rows);
+	if let Some((position, row, start)) = duration.loop_target {
+		println!("\tLoops to position {} row {} ({:.3}s)", position, row, start);
+	} else if duration.stopped {
+		println!("\tStops with F00, song terminates");
+	} else if duration.aborted {
+		println!("\tNeither stops nor loops within {} rows", duration.rows);
+	}
+	println!();
 }
 
 fn show_sample_info(module: &ptmf::PTModule) {
-	let mut number = 1;
-	for sample in module.sample_info.iter() {
-		println!("Sample number {} details", number);
+	for (i, sample) in module.sample_info.iter().enumerate() {
+		println!("Sample number {} details", i + 1);
 		println!("\tName: {}", sample.name);
 		println!("\tLength: {}b", sample.length * 2);
 		println!("\tFinetune: {}", sample.finetune);
 		println!("\tVolume: {}", sample.volume);
 		println!("\tRepeat start: {}b", sample.repeat_start * 2);
 		println!("\tRepeat length: {}b", sample.repeat_length * 2);
-		number += 1;
 	}
-	println!("");
+	println!();
 }
 
-fn show_sample_stats(module: &ptmf::PTModule) {
-	let used_samples = module.sample_info.iter().filter(|si| si.length > 0);
-	
-	let mut len = Stats::new();
-	let mut finetune = Stats::new();
-	let mut volume = Stats::new();
-	let mut repeat_start = Stats::new();
-	let mut repeat_length = Stats::new();
-
-	for sample in used_samples {
-		let slen = sample.length as u32 * 2;
-		let sfinetune = sample.finetune as u32;
-		let svolume = sample.volume as u32;
-		let srepeat_start = sample.repeat_start as u32 * 2;
-		let srepeat_length = sample.repeat_length as u32 * 2;
-	
-		len.update(slen);
-		finetune.update(sfinetune);
-		volume.update(svolume);
-		repeat_start.update(srepeat_start);
-		repeat_length.update(srepeat_length);
+fn show_stats(name: &str, stats: &analysis::Stats) {
+	println!("\t{} min: {} max: {} avg: {} median: {:.1} p10: {:.1} p90: {:.1} std dev: {:.1}",
+		name, stats.min, stats.max, stats.avg, stats.median, stats.p10, stats.p90, stats.std_dev);
+}
+
+fn show_sample_stats(module: &ptmf::PTModule) -> Result<()> {
+	for invalid in analysis::find_invalid_samples(module) {
+		println!("Error: Invalid sample number in Pattern '{}' Row '{}' Channel '{}' Sample number '{}'",
+			invalid.pattern, invalid.row, invalid.channel, invalid.sample_number);
 	}
-	
-	len.done();
-	finetune.done();
-	volume.done();
-	repeat_start.done();
-	repeat_length.done();
-	
-	let unused = find_unused_samples(module);
+
+	let stats = analysis::sample_stats(module);
 	
 	println!("Sample statistics");
-	println!("\tLength min: {} max: {} avg: {}", len.min, len.max, len.avg);
-	println!("\tFinetune min: {} max: {} avg: {}", finetune.min, finetune.max, finetune.avg);
-	println!("\tVolume min: {} max: {} avg: {}", volume.min, volume.max, volume.avg);
-	println!("\tRepeat start min: {} max: {} avg: {}", repeat_start.min, repeat_start.max, repeat_start.avg);
-	println!("\tRepeat length min: {} max: {} avg: {}", repeat_length.min, repeat_length.max, repeat_length.avg);
+	show_stats("Length", &stats.length);
+	show_stats("Finetune", &stats.finetune);
+	show_stats("Volume", &stats.volume);
+	show_stats("Repeat start", &stats.repeat_start);
+	show_stats("Repeat length", &stats.repeat_length);
+	println!("\tLength histogram:");
+	stats.length.write_histogram(&mut std::io::stdout(), 8, 40)?;
+	println!("\tVolume histogram:");
+	stats.volume.write_histogram(&mut std::io::stdout(), 8, 40)?;
 	print!("\tUnused samples: ");
-	for i in unused {
+	for i in stats.unused {
 		print!("{} ", i);
 	}
-	println!("");
-	println!("");
-}
+	println!();
 
-fn find_unused_patterns(module: &ptmf::PTModule) -> Vec<u8> {
-	let mut unused:Vec<u8> = Vec::new();
-	let positions = &module.positions.data[0..module.length as usize];
-	let num_patterns = module.patterns.len();
-	for i in 0..num_patterns as u8 {
-		if !positions.contains(&i) {
-			unused.push(i);
-		}
+	println!("\tAudio:");
+	for m in stats.metrics.iter() {
+		println!("\t Sample {:>2} peak: {:>3} rms: {:>6.2} dc offset: {:>6.2} clipped: {} trailing silence: {}b",
+			m.number, m.peak, m.rms, m.dc_offset, m.clipped, m.trailing_silence);
 	}
+	println!();
 
-	unused
+	Ok(())
 }
 
 fn show_pattern_info(module: &ptmf::PTModule, use_spn: bool) {
+	let info = analysis::pattern_info(module, use_spn);
 
 	println!("Pattern info");
 	print!("\tPattern play order: ");
-	let positions = &module.positions.data[0..module.length as usize];
-	for pos in positions.iter() {
+	for pos in info.play_order.iter() {
 		print!("{} ",pos);
 	}
-	println!("");
+	println!();
 	
 	print!("\tUnused patterns: ");
-	let unused = find_unused_patterns(module);
-	for i in unused {
+	for i in info.unused {
 		print!("{} ",i);
 	}
-	println!("");
+	println!();
 	
 	print!("\tEmpty patterns: ");
-	for i in 0..module.patterns.len() {
-		let mut empty = true;
-		for row in &module.patterns[i].rows {
-			for channel in &row.channels {
-				if channel.period != 0 ||
-					channel.sample_number != 0 ||
-					channel.effect != 0 {
-					empty = false;
-				}
-				if !empty {
-					break;
-				}
-			}
-			if !empty {
-				break;
-			}
-		}
-		if empty {
-			print!("{} ",i);
-		}
+	for i in info.empty {
+		print!("{} ",i);
 	}
-	println!("");
+	println!();
 	
 	println!("\tUsed periods: ");
-	let mut map = BTreeMap::<u16,u16>::new();
-	for pattern_no in 0..module.patterns.len() {
-		let ref pattern = module.patterns[pattern_no];
-		for row_no in 0..pattern.rows.len() {
-			let ref row = pattern.rows[row_no];
-			for channel_no in 0..row.channels.len() {
-				let ref channel = row.channels[channel_no];
-				if channel.period > 0 {
-					let count = map.entry(channel.period).or_insert(0);
-					*count += 1;
-				}
-			}
-		}
-	}
-	for key in map.keys() {
-		// Find the position in PERIODS with the
-		// smallest difference
-		let mut found:i32 = -1;
-		let mut min_diff = 65536;
-		let key = *key as i32;
-		for i in 0..ptmf::PERIODS.len() {
-			let diff = (key as i32 - ptmf::PERIODS[i] as i32).abs();
-			if diff < min_diff {
-				min_diff = diff;
-				found = i as i32;
-			}
-		}
-		
-		let note = if found == -1 {
+	for period in info.periods.iter() {
+		if period.note.is_empty() {
 			println!("Failed to find note name");
-			String::new()
-		} else {
-			let mut octave = found / 12;
-			if use_spn {
-				octave += 2;
-			}
-			let note = (found % 12) as usize;
-			let prefix = match min_diff {
-				0 => "",
-				_ => "~"
-			};
-			format!("{}{}-{}",prefix,ptmf::NOTE_NAMES[note],octave)
-		};
-		
-		println!("\t {}({}) ",key,note);
+		}
+		println!("\t {}({}) ",period.period,period.note);
 	}
-//	println!("");
 	
 	println!("\tUsed effects: ");
-	let mut effects = [false; 32]; // 32 effects
-	for pattern_no in 0..module.patterns.len() {
-		let ref pattern = module.patterns[pattern_no];
-		for row_no in 0..pattern.rows.len() {
-			let ref row = pattern.rows[row_no];
-			for channel_no in 0..row.channels.len() {
-				let ref channel = row.channels[channel_no];
-				let mut effect = (channel.effect & 0x0f00) >> 8;
-				if effect == 0 {
-					if channel.effect & 0x00ff == 0 {
-						// Not really an effect
-						continue;
-					}
-				}
-				if effect == 0xe {
-					effect = ((channel.effect & 0x00f0) >> 4) + 16;
-				}
-				effects[effect as usize] = true;
-			}
-		}
+	for name in info.effects.iter() {
+		println!("\t {}",name);
 	}
 	
-	let mut usecode:u32 = 0;
-	for i in 0..effects.len() {
-		if effects[i] {
-			let name = ptmf::EFFECT_NAMES[i];
-			println!("\t {}",name);
-			
-			// Figure out the player usecode
-			// Some have special handling
-			if i == 0 {
-				// 
-				usecode |= 1 << 8; // The player converts 0 to 8
-			} else {
-				usecode |= 1 << i;
-			} 
-		}
+	println!("\tThe Player usecode: ${:X}",info.usecode);
+	println!();
+}
+
+/// Builds a report with the selected show sections, csv is true if
+/// the report is written as CSV
+fn build_report(module: &ptmf::PTModule, filename: &str, args: &Args, csv: bool) -> Result<analysis::Report> {
+	if args.flag_patterns || args.flag_reachability || args.flag_duration {
+		return Err(anyhow!("--patterns, --reachability and --duration are only available as text"));
 	}
-	
-	// Check if finetune is used
-	for si in module.sample_info.iter() {
-		if si.finetune != 0 {
-			usecode |= 1;
-			break;
-		}
+	if csv && (args.flag_summary || args.flag_pattern_info) {
+		return Err(anyhow!("Only --sample-info and --sample-stats are available as csv"));
 	}
-	
-	println!("\tThe Player usecode: ${:X}",usecode);
-	println!("");
+
+	let with_samples = args.flag_sample_info || args.flag_sample_stats;
+	Ok(analysis::Report {
+		file: filename.to_string(),
+		summary: if args.flag_summary { Some(analysis::summary(module)) } else { None },
+		samples: if args.flag_sample_info { Some(analysis::sample_details(module)) } else { None },
+		sample_stats: if args.flag_sample_stats { Some(analysis::sample_stats(module)) } else { None },
+		invalid_samples: if with_samples { Some(analysis::find_invalid_samples(module)) } else { None },
+		pattern_info: if args.flag_pattern_info { Some(analysis::pattern_info(module, args.flag_use_spn)) } else { None },
+	})
 }
 
-fn save_samples(module: &ptmf::PTModule,range: &Vec<usize>,prefix: &String, use_sample_name: &bool) {
+fn save_samples(module: &ptmf::PTModule,range: &Vec<usize>,prefix: &String, use_sample_name: &bool, format: &SampleFormat, period: u16) {
+	let sample_rate = note::period_to_rate(period);
+	// MIDI note 60 is C-2
+	let unity_note = match note::find_nearest_note(period) {
+		Some((index, _)) => cmp::min(index + 36, 127) as u8,
+		None => 60,
+	};
+
 	for i in range {
 		let sample_name = sanitize_filename::sanitize(&module.sample_info[*i].name);
 		if module.sample_info[*i].length == 0 {
@@ -371,9 +669,9 @@ fn save_samples(module: &ptmf::PTModule,range: &Vec<usize>,prefix: &String, use_
 			continue;
 		}
 		let filename = if *use_sample_name {
-			format!("{}.raw",sample_name)
+			format!("{}.{}",sample_name,format.extension())
 		} else {
-			format!("{}_{}.raw",prefix,i+1)
+			format!("{}_{}.{}",prefix,i+1,format.extension())
 		};
 		println!("Writing sample: '{}'", filename);
 
@@ -388,8 +686,20 @@ fn save_samples(module: &ptmf::PTModule,range: &Vec<usize>,prefix: &String, use_
 			}
 		};
 
+		if *format == SampleFormat::Svx {
+			let dropped = iff::bytes_after_loop(&module.sample_info[*i]);
+			if dropped > 0 {
+				println!("Warning: 8SVX can not store the {} bytes after the loop of sample {}, they are not written", dropped, i + 1);
+			}
+		}
+
 		let mut writer = BufWriter::new(&file);		
-		match writer.write_all(&module.sample_info[*i].data) {
+		let result = match format {
+			SampleFormat::Raw => writer.write_all(&module.sample_info[*i].data),
+			SampleFormat::Wav => wav::write_sample(&mut writer, &module.sample_info[*i], sample_rate, unity_note),
+			SampleFormat::Svx => iff::write_sample(&mut writer, &module.sample_info[*i], sample_rate),
+		};
+		match result {
 			Ok(_) => (),
 			Err(e) => {
 				println!("Failed to write sample {}. Error: '{:?}'", i, e);
@@ -398,88 +708,35 @@ fn save_samples(module: &ptmf::PTModule,range: &Vec<usize>,prefix: &String, use_
 	}
 }
 
-fn remove_unused_patterns(module: &mut ptmf::PTModule) {
-	let mut unused = find_unused_patterns(module);
-	unused.reverse();
-	
-	// MUST Remove highest pattern first
-	for i in unused {
-		// Remove pattern
-		module.patterns.remove(i as usize);
-	
-		// Adjust play positions
-		for j in 0..module.length {
-			let j = j as usize;
-			if module.positions.data[j] > i {
-				module.positions.data[j] -= 1;
-			}
-		}
-	}
-}	
-
-fn find_unused_samples(module: &ptmf::PTModule) -> Vec<u8> {
-	let mut unused:Vec<u8> = Vec::new();
-	let mut used = [0u8;32];
-
-	// Find all used samples
-	for pattern_no in 0..module.patterns.len() {
-		let ref pattern = module.patterns[pattern_no];
-		for row_no in 0..pattern.rows.len() {
-			let ref row = pattern.rows[row_no];
-			for channel_no in 0..row.channels.len() {
-				let ref channel = row.channels[channel_no];
-				let number = channel.sample_number as usize;
-				if number > 0 {
-					if number > 31 {
-						println!("Error: Invalid sample number in Pattern '{}' Row '{}' Channel '{}' Sample number '{}'",pattern_no,row_no,channel_no,number);
-					} else {
-						used[number] = 1;
-					}
-				}				
-			}
-		}
+/// A WAV file written while a song is rendered
+struct WavFile {
+	filename: String,
+	writer: wav::Pcm16Writer<BufWriter<File>>,
+}
+
+impl WavFile {
+	fn create(filename: String, channels: u16, sample_rate: u32) -> Result<WavFile> {
+		let file = File::create(&filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let writer = wav::Pcm16Writer::new(BufWriter::new(file), channels, sample_rate)
+			.with_context(|| format!("Failed to write file: '{}'", filename))?;
+		Ok(WavFile{filename, writer})
 	}
 
-	// Find all unused samples
-	for i in 1..module.sample_info.len()+1 {
-		if used[i] == 0 {
-			unused.push(i as u8);
-		}
+	fn write(&mut self, samples: &[i16]) -> Result<()> {
+		let filename = &self.filename;
+		self.writer.write(samples)
+			.with_context(|| format!("Failed to write file: '{}'", filename))
 	}
-	
-	unused
-}
 
-fn remove_unused_samples(module: &mut ptmf::PTModule) {
-	let mut unused = find_unused_samples(module);
-	// MUST remove highest sample first
-	unused.sort();
-	unused.reverse();
+	fn finish(self) -> Result<()> {
+		let filename = self.filename;
+		self.writer.finish()
+			.with_context(|| format!("Failed to write file: '{}'", filename))?;
 
-	for i in unused {
-		let index = i as usize - 1;
-		
-		// Remove sample info and put it last
-		let mut si = module.sample_info.remove(index);
-				
-		si.length = 0;
-		si.repeat_start = 0;
-		si.repeat_length = 0;
-		si.data.clear();
-		module.sample_info.push(si);
-	
-		// Rewrite instrument references
-		// TODO optimize this
-		for pattern in &mut module.patterns {
-			for row in &mut pattern.rows {
-				for channel in &mut row.channels {
-					let number = channel.sample_number;
-					if number > i {
-						channel.sample_number -= 1;
-					}				
-				}
-			}
-		}
+		println!("Writing: '{}'", filename);
+		Ok(())
 	}
 }
 
@@ -493,27 +750,37 @@ fn main() -> Result<()> {
 		return Ok(());
 	}
 	
-	if args.flag_number.len() > 0 {
-		let number = usize::from_str(&args.flag_number).unwrap();
-		if number < 1 || number > 31 {
+	if !args.flag_number.is_empty() {
+		let number = usize::from_str(&args.flag_number)
+			.with_context(|| format!("Invalid sample number '{}'", args.flag_number))?;
+		if !(1..=31).contains(&number) {
 			return Err(anyhow!("Invalid sample number '{}'", number));
 		}
 	}
 
-	fn mod_fn_true(reader: &mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> {
-		return ptmf::read_mod(reader, true);
+	// The format is detected, --in-p61 is only needed if detection fails
+	fn mod_fn_true(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+		let (module, warnings) = loader::read_with_warnings(reader, true)?;
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		Ok(module)
 	}
 
-	fn mod_fn_false(reader: &mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> {
-		return ptmf::read_mod(reader, false);
+	fn mod_fn_false(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+		let (module, warnings) = loader::read_with_warnings(reader, false)?;
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		Ok(module)
 	}
 
 	let skip_file_size_check = args.flag_skip_filesize_check;
 
 	let p61 = args.flag_in_p61;
-	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> = 
+	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule> = 
 		if p61 {
-			ptmf::read_p61
+			loader::read_p61
 		} else {
 			if skip_file_size_check {
 				mod_fn_true
@@ -523,7 +790,14 @@ fn main() -> Result<()> {
 		};
 		
 	if args.cmd_show {
-		for ref filename in args.arg_file {
+		let csv = match args.flag_format.to_lowercase().as_str() {
+			"" | "text" => None,
+			"json" => Some(false),
+			"csv" => Some(true),
+			_ => return Err(anyhow!("Invalid output format '{}'", args.flag_format)),
+		};
+		let mut reports = Vec::new();
+		for filename in &args.arg_file {
 			let file = File::open(filename)
 				.with_context(|| format!("Failed to open file: '{}'", filename))?;
 			
@@ -535,6 +809,17 @@ fn main() -> Result<()> {
 				}
 			};
 			
+			if let Some(csv) = csv {
+				let report = build_report(&module, filename, &args, csv)?;
+				if csv {
+					reports.push(report);
+				} else {
+					let stdout = std::io::stdout();
+					analysis::write_json(&mut stdout.lock(), &report)?;
+				}
+				continue;
+			}
+
 			println!("Processing: {}", filename);
 				
 			if args.flag_summary {
@@ -546,15 +831,38 @@ fn main() -> Result<()> {
 			}
 			
 			if args.flag_sample_stats {
-				show_sample_stats(&module);
+				show_sample_stats(&module)?;
 			}
 			
 			if args.flag_pattern_info {
 				show_pattern_info(&module, args.flag_use_spn);
 			}
+
+			if args.flag_patterns {
+				show_patterns(&module, &args.flag_pattern, &args.flag_position, args.flag_use_spn, args.flag_highlight_sync)?;
+			}
+
+			if args.flag_reachability {
+				show_reachability(&module);
+			}
+
+			if args.flag_duration {
+				show_duration(&module, !args.flag_vblank);
+			}
+		}
+
+		if csv == Some(true) {
+			let stdout = std::io::stdout();
+			analysis::write_csv(&mut stdout.lock(), &reports)?;
 		}
 	} else if args.cmd_save {
-		for ref filename in args.arg_file {
+		let sample_format = SampleFormat::from_str(&args.flag_format)?;
+		let period = match note::parse_period(&args.flag_note, false) {
+			Some(period) => period,
+			None => return Err(anyhow!("Invalid note '{}'", args.flag_note)),
+		};
+
+		for filename in &args.arg_file {
 			let file = File::open(filename)
 				.with_context(|| format!("Failed to open file: '{}'", filename))?;
 			
@@ -571,14 +879,15 @@ fn main() -> Result<()> {
 			let range = if args.flag_all {
 				0..module.sample_info.len()
 			} else {
-				let number = usize::from_str(&args.flag_number).unwrap() - 1;
+				let number = usize::from_str(&args.flag_number)
+					.with_context(|| format!("Invalid sample number '{}'", args.flag_number))? - 1;
 				if number >= module.sample_info.len() {
 					return Err(anyhow!("Invalid sample number. Only {} samples available.", module.sample_info.len()))
 				}
 				number..number+1
 			};
 			
-			save_samples(&module,&(range.collect()),&args.arg_fileprefix, &args.flag_use_sample_name);
+			save_samples(&module,&(range.collect()),&args.arg_fileprefix, &args.flag_use_sample_name, &sample_format, period);
 		}
 	} else if args.cmd_convert {
 		for ref filename in args.arg_file {
@@ -595,12 +904,44 @@ fn main() -> Result<()> {
 			
 			println!("Processing: {}", filename);
 			
-			if args.flag_unused_patterns {
-				remove_unused_patterns(&mut module);
+			if args.flag_unreachable {
+				let reachability = reachability::analyze(&module);
+				if reachability.aborted {
+					return Err(anyhow!("Song in '{}' neither stops nor loops, unable to find unreachable data", filename));
+				}
+				let (positions, rows) = reachability::remove_unreachable(&mut module, &reachability);
+				println!("Removed {} positions and cleared {} rows", positions, rows);
+			}
+
+			if args.flag_duplicate_patterns {
+				let saved = transform::remove_duplicate_patterns(&mut module);
+				println!("Removed duplicate patterns, saved {} bytes", saved);
+			}
+
+			// Before removing unused samples, the cleared duplicates are unused
+			if args.flag_duplicate_samples {
+				let saved = transform::remove_duplicate_samples(&mut module, args.flag_bake_volume);
+				println!("Removed duplicate samples, saved {} bytes", saved);
+			}
+
+			// Unreachable patterns are no longer in the order list
+			if args.flag_unused_patterns || args.flag_unreachable {
+				transform::remove_unused_patterns(&mut module);
 			}
 			
 			if args.flag_unused_samples {
-				remove_unused_samples(&mut module);
+				transform::remove_unused_samples(&mut module);
+			}
+
+			if args.flag_trim_samples {
+				let trimmed = transform::trim_samples(&mut module);
+				let mut saved = 0;
+				for t in trimmed.iter() {
+					println!("Trimmed sample {} from {} to {} bytes, saved {} bytes",
+						t.number, t.old_length, t.new_length, t.old_length - t.new_length);
+					saved += t.old_length - t.new_length;
+				}
+				println!("Trimmed {} samples, saved {} bytes", trimmed.len(), saved);
 			}
 			
 			let filename = format!("{}_{}",args.arg_fileprefix,filename);
@@ -609,7 +950,7 @@ fn main() -> Result<()> {
 				.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 			let mut writer = BufWriter::new(&file);		
-			match ptmf::write_mod(&mut writer,&mut module) {
+			match multichannel::write_mod(&mut writer, &mut module) {
 				Ok(_) => (),
 				Err(e) => {
 					return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
@@ -618,7 +959,7 @@ fn main() -> Result<()> {
 		}
 	}  else if args.cmd_merge {
 		// Open first module
-		let ref first_filename = args.arg_file[0];
+		let first_filename = &args.arg_file[0];
 		let file = File::open(first_filename)
 			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
 		
@@ -634,12 +975,12 @@ fn main() -> Result<()> {
 		drop(file);
 
 		for i in 1..args.arg_file.len() {
-			let ref filename = args.arg_file[i];
+			let filename = &args.arg_file[i];
 			let file = File::open(filename)
 				.with_context(|| format!("Failed to open file: '{}'", filename))?;
 			
 			let mut reader = BufReader::new(&file);
-			let mut module = match read_fn(&mut reader) {
+			let module = match read_fn(&mut reader) {
 				Ok(module) => module,
 				Err(e) => {
 					return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", filename, e))
@@ -648,43 +989,17 @@ fn main() -> Result<()> {
 			
 			println!("Processing: {}", filename);
 
-			let new_offset = first_module.patterns.len() as u8;
-
-			for pattern in &mut module.patterns {
-				if args.flag_sync {
-					for row in &mut pattern.rows {
-						for channel in &mut row.channels {
-							channel.period = 0;
-							channel.sample_number = 0;
-							let mut effect = 0 as u16;
-							if channel.effect & 0x0ff0 == 0x0e80 {
-								effect = channel.effect;
-							} else if channel.effect & 0x0f00 == 0x0f00 {
-								effect = channel.effect;
-							} else if channel.effect & 0x0d00 == 0x0d00 {
-								effect = channel.effect;
-							} else if channel.effect & 0x0b00 == 0x0b00 {
-								effect = channel.effect;
-							}
-							channel.effect = effect;
-						}
-					}
-				}
-				first_module.patterns.push(pattern.clone())
-			}
-
-			for i in 0..module.length as usize {
-				first_module.positions.data[first_module.length as usize] = module.positions.data[i] + new_offset;
-				first_module.length += 1 as u8;
+			if let Err(e) = transform::append(&mut first_module, &module, args.flag_sync) {
+				return Err(anyhow!("Unable to merge '{}'. Error: '{}'", filename, e));
 			}
 		}
 
-		let ref filename = args.arg_target;
-		let file = File::create(&filename)
+		let filename = &args.arg_target;
+		let file = File::create(filename)
 			.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 		let mut writer = BufWriter::new(&file);		
-		match ptmf::write_mod(&mut writer,&mut first_module) {
+		match multichannel::write_mod(&mut writer, &mut first_module) {
 			Ok(_) => (),
 			Err(e) => {
 				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
@@ -693,7 +1008,7 @@ fn main() -> Result<()> {
 
 	}  else if args.cmd_insert {
 		// Open first module
-		let ref first_filename = args.arg_file[0];
+		let first_filename = &args.arg_file[0];
 		let file = File::open(first_filename)
 			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
 		
@@ -708,62 +1023,500 @@ fn main() -> Result<()> {
 		// Close file
 		drop(file);
 
-		for pattern in &mut module.patterns {
+		let result = transform::insert_sync(&mut module);
+		for (pattern_no, inserted) in result.iter().enumerate() {
+			match inserted {
+				transform::SyncInsert::HasSync => println!("Pattern {} has e8 command", pattern_no),
+				transform::SyncInsert::Inserted{..} => (),
+				transform::SyncInsert::NoRoom => println!("Failed to add e8 to pattern {}", pattern_no),
+			}
+		}
 
-			// Check if this pattern has e8 command
-			let mut has_e8 = false;
-			for row in &mut pattern.rows {
-				for channel in &mut row.channels {
-					if channel.effect & 0x0ff0 == 0x0e80 {
-						has_e8 = true;
-						break;
-					}
+		let filename = &args.arg_target;
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let mut writer = BufWriter::new(&file);		
+		match multichannel::write_mod(&mut writer, &mut module) {
+			Ok(_) => (),
+			Err(e) => {
+				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
+			}
+		}
+
+	}  else if args.cmd_replace_sample {
+		let number = usize::from_str(&args.flag_number)
+			.with_context(|| format!("Invalid sample number '{}'", args.flag_number))?;
+		let period = match note::parse_period(&args.flag_note, false) {
+			Some(period) => period,
+			None => return Err(anyhow!("Invalid note '{}'", args.flag_note)),
+		};
+
+		let first_filename = &args.arg_file[0];
+		let file = File::open(first_filename)
+			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
+		
+		let mut reader = BufReader::new(&file);
+		let mut module = match read_fn(&mut reader) {
+			Ok(module) => module,
+			Err(e) => {
+				return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", first_filename, e))
+			}
+		};
+
+		// Close file
+		drop(file);
+
+		if number > module.sample_info.len() {
+			return Err(anyhow!("Invalid sample number. Only {} samples available.", module.sample_info.len()))
+		}
+
+		let sample_filename = &args.arg_samplefile;
+		let mut buf = Vec::new();
+		File::open(sample_filename)
+			.and_then(|mut file| file.read_to_end(&mut buf))
+			.with_context(|| format!("Failed to read file: '{}'", sample_filename))?;
+
+		let mut sample = sample::load(&buf)
+			.with_context(|| format!("Failed to parse file: '{}'", sample_filename))?;
+		if sample.name.is_none() {
+			// Use the filename without extension as name
+			sample.name = std::path::Path::new(sample_filename).file_stem()
+				.map(|stem| stem.to_string_lossy().to_string());
+		}
+		if args.flag_resample {
+			sample.resample(note::period_to_rate(period))
+				.with_context(|| format!("Failed to resample: '{}'", sample_filename))?;
+		}
+		sample.apply(&mut module.sample_info[number - 1])
+			.with_context(|| format!("Failed to import: '{}'", sample_filename))?;
+
+		println!("Replaced sample {} with '{}', {} bytes", number, sample_filename, module.sample_info[number - 1].data.len());
+
+		let filename = &args.arg_target;
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let mut writer = BufWriter::new(&file);		
+		match multichannel::write_mod(&mut writer, &mut module) {
+			Ok(_) => (),
+			Err(e) => {
+				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
+			}
+		}
+	}  else if args.cmd_import_patterns {
+		let first_filename = &args.arg_file[0];
+		let file = File::open(first_filename)
+			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
+		
+		let mut reader = BufReader::new(&file);
+		let mut module = match read_fn(&mut reader) {
+			Ok(module) => module,
+			Err(e) => {
+				return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", first_filename, e))
+			}
+		};
+
+		// Close file
+		drop(file);
+
+		let text_filename = &args.arg_textfile;
+		let text = std::fs::read_to_string(text_filename)
+			.with_context(|| format!("Failed to read file: '{}'", text_filename))?;
+		let patterns = text::parse_patterns(&text, args.flag_use_spn)
+			.with_context(|| format!("Failed to parse file: '{}'", text_filename))?;
+
+		for pattern in &patterns {
+			text::apply_pattern(&mut module, pattern)
+				.with_context(|| format!("Failed to import: '{}'", text_filename))?;
+			println!("Imported pattern {}", pattern.number);
+		}
+
+		let filename = &args.arg_target;
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let mut writer = BufWriter::new(&file);		
+		match multichannel::write_mod(&mut writer, &mut module) {
+			Ok(_) => (),
+			Err(e) => {
+				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
+			}
+		}
+	}  else if args.cmd_diff {
+		let mut modules = Vec::new();
+		for filename in &[&args.arg_file[0], &args.arg_other] {
+			let file = File::open(filename)
+				.with_context(|| format!("Failed to open file: '{}'", filename))?;
+			
+			let mut reader = BufReader::new(&file);
+			let module = match read_fn(&mut reader) {
+				Ok(module) => module,
+				Err(e) => {
+					return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", filename, e))
 				}
-				if has_e8 {
-					break;
+			};
+			modules.push(module);
+		}
+
+		let differences = diff::diff(&modules[0], &modules[1], args.flag_use_spn);
+
+		let stdout = std::io::stdout();
+		let mut writer = stdout.lock();
+		if args.flag_json {
+			diff::write_json(&mut writer, &differences)?;
+		} else {
+			diff::write_text(&mut writer, &differences, args.flag_use_spn)?;
+		}
+	}  else if args.cmd_merge3 {
+		let mut modules = Vec::new();
+		for filename in &[&args.arg_base, &args.arg_ours, &args.arg_theirs] {
+			let file = File::open(filename)
+				.with_context(|| format!("Failed to open file: '{}'", filename))?;
+			
+			let mut reader = BufReader::new(&file);
+			let module = match read_fn(&mut reader) {
+				Ok(module) => module,
+				Err(e) => {
+					return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", filename, e))
 				}
+			};
+			modules.push(module);
+		}
+		let theirs = modules.pop().unwrap();
+		let mut ours = modules.pop().unwrap();
+		let base = modules.pop().unwrap();
+
+		let conflicts = merge3::merge(&base, &mut ours, &theirs);
+
+		let filename = &args.arg_target;
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let mut writer = BufWriter::new(&file);		
+		match multichannel::write_mod(&mut writer, &mut ours) {
+			Ok(_) => (),
+			Err(e) => {
+				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
 			}
+		}
 
-			// Skip if pattern has e8 command
-			if has_e8 {
-				println!("has e8 command");
-				continue;
+		if !args.flag_conflicts.is_empty() {
+			let filename = &args.flag_conflicts;
+			let file = File::create(filename)
+				.with_context(|| format!("Failed to open file: '{}'", filename))?;
+			let mut writer = BufWriter::new(&file);
+			merge3::write_conflicts(&mut writer, &conflicts)
+				.with_context(|| format!("Failed to write file: '{}'", filename))?;
+		} else if !conflicts.is_empty() {
+			let stdout = std::io::stdout();
+			merge3::write_conflicts(&mut stdout.lock(), &conflicts)?;
+		}
+		if !conflicts.is_empty() {
+			return Err(anyhow!("Merged with {} conflicts", conflicts.len()));
+		}
+		eprintln!("Merged with 0 conflicts");
+	}  else if args.cmd_transpose {
+		let semitones = i32::from_str(&args.flag_semitones)
+			.with_context(|| format!("Invalid semitones '{}'", args.flag_semitones))?;
+		let selection = parse_selection(&args.flag_pattern, &args.flag_channel, &args.flag_sample)?;
+
+		let first_filename = &args.arg_file[0];
+		let file = File::open(first_filename)
+			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
+		
+		let mut reader = BufReader::new(&file);
+		let mut module = match read_fn(&mut reader) {
+			Ok(module) => module,
+			Err(e) => {
+				return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", first_filename, e))
+			}
+		};
+
+		// Close file
+		drop(file);
+
+		for pattern_no in transform::track_samples(&module).ambiguous {
+			eprintln!("Warning: Pattern {} is played with different samples, using the samples from the first time it is played", pattern_no);
+		}
+
+		let out_of_range = transform::transpose(&mut module, semitones, &selection, args.flag_clamp);
+		for note in &out_of_range {
+			println!("Out of range: pattern {} row {} channel {} period {} ({})",
+				note.pattern, note.row, note.channel + 1, note.period, note::tracker_name(note.period, false));
+		}
+		if !out_of_range.is_empty() && !args.flag_clamp {
+			return Err(anyhow!("{} notes out of range, use --clamp to write anyway", out_of_range.len()));
+		}
+
+		let filename = &args.arg_target;
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let mut writer = BufWriter::new(&file);		
+		match multichannel::write_mod(&mut writer, &mut module) {
+			Ok(_) => (),
+			Err(e) => {
+				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
 			}
+		}
+	}  else if args.cmd_channels {
+		let selection = parse_selection(&args.flag_pattern, "", "")?;
+
+		let first_filename = &args.arg_file[0];
+		let file = File::open(first_filename)
+			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
+		
+		let mut reader = BufReader::new(&file);
+		let mut module = match read_fn(&mut reader) {
+			Ok(module) => module,
+			Err(e) => {
+				return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", first_filename, e))
+			}
+		};
+
+		// Close file
+		drop(file);
+
+		if !args.flag_copy.is_empty() {
+			let channels = parse_channel_list(&args.flag_copy, Some(2))?;
+			let source_filename = &args.flag_from;
+			let file = File::open(source_filename)
+				.with_context(|| format!("Failed to open file: '{}'", source_filename))?;
+			
+			let mut reader = BufReader::new(&file);
+			let source = match read_fn(&mut reader) {
+				Ok(module) => module,
+				Err(e) => {
+					return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", source_filename, e))
+				}
+			};
+			transform::copy_channel(&mut module, &source, channels[0], channels[1], &selection)?;
+		}
+
+		if !args.flag_swap.is_empty() {
+			let channels = parse_channel_list(&args.flag_swap, Some(2))?;
+			transform::swap_channels(&mut module, channels[0], channels[1], &selection)?;
+		}
+
+		if !args.flag_order.is_empty() {
+			let order = parse_channel_list(&args.flag_order, None)?;
+			transform::reorder_channels(&mut module, &order, &selection)?;
+		}
+
+		if !args.flag_clear.is_empty() {
+			let selection = parse_selection(&args.flag_pattern, &args.flag_clear, "")?;
+			transform::clear_channels(&mut module, &selection, args.flag_keep_effects);
+		}
 
-			// insert e8 command
-			let mut added_e8 = false;
-			for row in &mut pattern.rows {
-				for channel in &mut row.channels {
-					if channel.effect == 0x0 {
-						channel.effect = 0x0e81;
-						added_e8 = true;
-						break;
+		let filename = &args.arg_target;
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let mut writer = BufWriter::new(&file);		
+		match multichannel::write_mod(&mut writer, &mut module) {
+			Ok(_) => (),
+			Err(e) => {
+				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
+			}
+		}
+	}  else if args.cmd_render {
+		let sample_rate = u32::from_str(&args.flag_rate)
+			.with_context(|| format!("Invalid sample rate '{}'", args.flag_rate))?;
+		let max_seconds = if !args.flag_seconds.is_empty() {
+			Some(f64::from_str(&args.flag_seconds)
+				.with_context(|| format!("Invalid seconds '{}'", args.flag_seconds))?)
+		} else {
+			None
+		};
+
+		let first_filename = &args.arg_file[0];
+		let file = File::open(first_filename)
+			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
+		
+		let mut reader = BufReader::new(&file);
+		let module = match read_fn(&mut reader) {
+			Ok(module) => module,
+			Err(e) => {
+				return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", first_filename, e))
+			}
+		};
+
+		// Close file
+		drop(file);
+
+		println!("Processing: {}", first_filename);
+
+		let filename = &args.arg_target;
+		let num_channels = multichannel::num_channels(&module);
+		let mut mix = WavFile::create(filename.to_string(), 2, sample_rate)?;
+
+		// Stems have the same length as the mix
+		let prefix = filename.strip_suffix(".wav").unwrap_or(filename);
+		let mut stems = Vec::new();
+		if args.flag_stems {
+			for channel_no in 0..num_channels {
+				stems.push(WavFile::create(format!("{}_ch{}.wav", prefix, channel_no + 1), 1, sample_rate)?);
+			}
+		}
+		// Sample stems are created when the sample is first played,
+		// starting with silence
+		let mut sample_stems = BTreeMap::new();
+		let mut frames = 0;
+
+		let seconds = replayer::render_song(&module, sample_rate, !args.flag_vblank, max_seconds, &mut |tick| {
+			mix.write(&replayer::mix_stereo(tick.values, tick.num_channels))?;
+			for (channel_no, stem) in stems.iter_mut().enumerate() {
+				stem.write(&replayer::channel_mono(tick.values, tick.num_channels, channel_no))?;
+			}
+			if args.flag_sample_stems {
+				for number in tick.sample_numbers {
+					if *number == 0 || sample_stems.contains_key(number) {
+						continue;
 					}
+					let mut stem = WavFile::create(format!("{}_s{}.wav", prefix, number), 2, sample_rate)?;
+					stem.write(&vec![0; frames * 2])?;
+					sample_stems.insert(*number, stem);
 				}
-				if added_e8 {
-					break;
+				for (number, stem) in sample_stems.iter_mut() {
+					stem.write(&replayer::mix_sample(tick.values, tick.sample_numbers, tick.num_channels, *number))?;
 				}
 			}
-			if added_e8 {
-				continue;
+			frames += tick.values.len() / tick.num_channels.max(1);
+			Ok(())
+		})?;
+
+		mix.finish()?;
+		for stem in stems {
+			stem.finish()?;
+		}
+		for (_, stem) in sample_stems {
+			stem.finish()?;
+		}
+
+		println!("Rendered {:.2} seconds", seconds);
+	}  else if args.cmd_timeline {
+		let filename = &args.arg_target;
+		let format = match timeline::Format::from_filename(filename) {
+			Some(format) => format,
+			None => return Err(anyhow!("Unknown timeline format for '{}', use .json, .csv, .h or .s", filename)),
+		};
+
+		let first_filename = &args.arg_file[0];
+		let file = File::open(first_filename)
+			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
+		
+		let mut reader = BufReader::new(&file);
+		let module = match read_fn(&mut reader) {
+			Ok(module) => module,
+			Err(e) => {
+				return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", first_filename, e))
+			}
+		};
+
+		// Close file
+		drop(file);
+
+		println!("Processing: {}", first_filename);
+
+		let events = timeline::sync_events(&module, !args.flag_vblank);
+
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
+
+		let mut writer = BufWriter::new(&file);
+		timeline::write(&mut writer, &events, &format)
+			.with_context(|| format!("Failed to write file: '{}'", filename))?;
+
+		println!("Wrote {} E8x events to '{}'", events.len(), filename);
+	}  else if args.cmd_resample {
+		let number = u8::from_str(&args.flag_number)
+			.with_context(|| format!("Invalid sample number '{}'", args.flag_number))?;
+		let factor = f64::from_str(&args.flag_factor)
+			.with_context(|| format!("Invalid factor '{}'", args.flag_factor))?;
+
+		let first_filename = &args.arg_file[0];
+		let file = File::open(first_filename)
+			.with_context(|| format!("Failed to open file: '{}'", first_filename))?;
+		
+		let mut reader = BufReader::new(&file);
+		let mut module = match read_fn(&mut reader) {
+			Ok(module) => module,
+			Err(e) => {
+				return Err(anyhow!("Failed to parse file: '{}' Error: '{:?}'", first_filename, e))
 			}
+		};
 
-			println!("Failed to add e8 to pattern {:#?}", pattern)
+		// Close file
+		drop(file);
+
+		let old_length = (number as usize).checked_sub(1)
+			.and_then(|i| module.sample_info.get(i))
+			.map(|si| si.data.len())
+			.unwrap_or(0);
+		for pattern_no in transform::track_samples(&module).ambiguous {
+			eprintln!("Warning: Pattern {} is played with different samples, using the samples from the first time it is played", pattern_no);
 		}
 
-		let ref filename = args.arg_target;
-		let file = File::create(&filename)
+		let warnings = transform::resample_sample(&mut module, number, factor, args.flag_zero_start)?;
+		for note in &warnings.out_of_range {
+			println!("Warning: Out of range: pattern {} row {} channel {} period {} ({})",
+				note.pattern, note.row, note.channel + 1, note.period, note::tracker_name(note.period, false));
+		}
+		for offset in &warnings.offsets {
+			println!("Warning: Sample offset out of range: pattern {} row {} channel {} offset ${:X}, using $FF",
+				offset.pattern, offset.row, offset.channel + 1, offset.offset);
+		}
+		for slide in &warnings.slides {
+			println!("Warning: Portamento speed out of range: pattern {} row {} channel {} effect {:03X}, using {:03X}",
+				slide.pattern, slide.row, slide.channel + 1, slide.effect, slide.new_effect);
+		}
+		if warnings.zeroed_start {
+			println!("Warning: Set the first two bytes of sample {} to zero", number);
+		}
+		println!("Resampled sample {} from {} to {} bytes",
+			number, old_length, module.sample_info[number as usize - 1].data.len());
+
+		let filename = &args.arg_target;
+		let file = File::create(filename)
 			.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 		let mut writer = BufWriter::new(&file);		
-		match ptmf::write_mod(&mut writer,&mut module) {
+		match multichannel::write_mod(&mut writer, &mut module) {
 			Ok(_) => (),
 			Err(e) => {
 				return Err(anyhow!("Failed to write module {}. Error: '{:?}'", filename, e))
 			}
 		}
+	}  else if args.cmd_collection {
+		let json = match args.flag_format.to_lowercase().as_str() {
+			"" | "text" => false,
+			"json" => true,
+			_ => return Err(anyhow!("Invalid output format '{}'", args.flag_format)),
+		};
+
+		let mut stats = collection::CollectionStats::new();
+		for path in args.arg_path.iter() {
+			let files = stats.find_files(Path::new(path));
+			for file in files {
+				stats.add_file(&file, args.flag_skip_filesize_check);
+			}
+		}
+		stats.done();
+
+		let filename = &args.arg_target;
+		let file = File::create(filename)
+			.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
-	} 
+		let mut writer = BufWriter::new(&file);
+		if json {
+			collection::write_json(&mut writer, &stats)
+		} else {
+			collection::write_text(&mut writer, &stats)
+		}.with_context(|| format!("Failed to write file: '{}'", filename))?;
+
+		println!("Processed {} files, {} modules and {} failures", stats.files, stats.modules, stats.failures.len());
+	}
 
 	Ok(())
 }
@@ -10,10 +10,13 @@ use serde::Deserialize;
 
 // ProTracker and ThePlayer
 use modfile::ptmf;
+// Input format detection and multichannel modules
+use modtool::loader;
+use modtool::multichannel;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 p612mod.
 
 Usage: 
@@ -46,9 +49,22 @@ fn main() -> Result<()> {
 		return Ok(());
 	}
 	
-	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule, ptmf::PTMFError> = ptmf::read_p61;
+	// The format is detected, any supported input works. Files that
+	// are not detected are read as The Player 6.1A, some P61 files
+	// have neither a signature nor a header the detection recognizes.
+	fn read_any(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+		let mut buf = Vec::new();
+		reader.read_to_end(&mut buf)?;
+		let format = loader::detect(&buf).unwrap_or(loader::Format::P61);
+		let (module, warnings) = loader::load_as_with_warnings(&buf, format, false)?;
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		Ok(module)
+	}
+	let read_fn:fn (&mut dyn Read) -> Result<ptmf::PTModule> = read_any;
 		
-	let ref filename = args.arg_source;
+	let filename = &args.arg_source;
 	let file = File::open(filename)
 			.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
@@ -61,12 +77,12 @@ fn main() -> Result<()> {
 		}
 	};
 		
-	let ref filename = args.arg_destination;
-	let file = File::create(&filename)
+	let filename = &args.arg_destination;
+	let file = File::create(filename)
 		.with_context(|| format!("Failed to open file: '{}'", filename))?;
 
 	let mut writer = BufWriter::new(&file);		
-	match ptmf::write_mod(&mut writer,&mut module) {
+	match multichannel::write_mod(&mut writer, &mut module) {
 		Ok(_) => (),
 		Err(e) => {
 			return Err(anyhow!("Failed to write module : '{}' Error: '{:?}'", filename, e))
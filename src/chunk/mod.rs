@@ -0,0 +1,122 @@
+use std::io::{self, Write};
+use anyhow::{Result, anyhow};
+
+/// Byte order of the sizes and values in a file,
+/// RIFF is little endian and IFF is big endian
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+	Little,
+	Big,
+}
+
+impl ByteOrder {
+	pub fn u16_bytes(self, val: u16) -> [u8; 2] {
+		match self {
+			ByteOrder::Little => val.to_le_bytes(),
+			ByteOrder::Big => val.to_be_bytes(),
+		}
+	}
+
+	pub fn u32_bytes(self, val: u32) -> [u8; 4] {
+		match self {
+			ByteOrder::Little => val.to_le_bytes(),
+			ByteOrder::Big => val.to_be_bytes(),
+		}
+	}
+
+	pub fn read_u16(self, buf: &[u8], offset: usize) -> u16 {
+		let bytes = [buf[offset], buf[offset + 1]];
+		match self {
+			ByteOrder::Little => u16::from_le_bytes(bytes),
+			ByteOrder::Big => u16::from_be_bytes(bytes),
+		}
+	}
+
+	pub fn read_u32(self, buf: &[u8], offset: usize) -> u32 {
+		let bytes = [buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]];
+		match self {
+			ByteOrder::Little => u32::from_le_bytes(bytes),
+			ByteOrder::Big => u32::from_be_bytes(bytes),
+		}
+	}
+}
+
+/// A RIFF or IFF chunk, id and data without padding
+pub struct Chunk {
+	pub id: [u8; 4],
+	pub data: Vec<u8>,
+}
+
+impl Chunk {
+	pub fn new(id: &[u8; 4], data: Vec<u8>) -> Chunk {
+		Chunk{id: *id, data}
+	}
+}
+
+/// Writes a container, like RIFF WAVE or FORM 8SVX, with chunks.
+/// Odd sized chunks are padded as required by both RIFF and IFF.
+pub fn write_container(writer: &mut dyn Write, order: ByteOrder, id: &[u8; 4], form_type: &[u8; 4], chunks: &[Chunk]) -> io::Result<()> {
+	let mut size: u32 = 4;
+	for chunk in chunks {
+		size += 8 + chunk.data.len() as u32 + (chunk.data.len() as u32 & 1);
+	}
+
+	writer.write_all(id)?;
+	writer.write_all(&order.u32_bytes(size))?;
+	writer.write_all(form_type)?;
+	for chunk in chunks {
+		writer.write_all(&chunk.id)?;
+		writer.write_all(&order.u32_bytes(chunk.data.len() as u32))?;
+		writer.write_all(&chunk.data)?;
+		if chunk.data.len() & 1 == 1 {
+			writer.write_all(&[0])?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Splits a container written by write_container into chunks
+pub fn read_container(buf: &[u8], order: ByteOrder, id: &[u8; 4], form_type: &[u8; 4]) -> Result<Vec<Chunk>> {
+	if buf.len() < 12 || &buf[0..4] != id || &buf[8..12] != form_type {
+		return Err(anyhow!("Not a {} {} file", String::from_utf8_lossy(id), String::from_utf8_lossy(form_type)));
+	}
+
+	let mut chunks = Vec::new();
+	let mut offset = 12;
+	while offset + 8 <= buf.len() {
+		let mut id = [0u8; 4];
+		id.copy_from_slice(&buf[offset..offset + 4]);
+		let size = order.read_u32(buf, offset + 4) as usize;
+		let start = offset + 8;
+		// Be forgiving about truncated files
+		let end = (start + size).min(buf.len());
+		chunks.push(Chunk{id, data: buf[start..end].to_vec()});
+		offset = start + size + (size & 1);
+	}
+
+	Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip_with_padding() {
+		for order in [ByteOrder::Little, ByteOrder::Big].iter() {
+			let chunks = [Chunk::new(b"odd ", vec![1, 2, 3]), Chunk::new(b"even", vec![4, 5])];
+			let mut buf = Vec::new();
+			write_container(&mut buf, *order, b"RIFF", b"TEST", &chunks).unwrap();
+			assert_eq!(buf.len(), 12 + 8 + 4 + 8 + 2);
+			assert_eq!(order.read_u32(&buf, 4), buf.len() as u32 - 8);
+
+			let read = read_container(&buf, *order, b"RIFF", b"TEST").unwrap();
+			assert_eq!(read.len(), 2);
+			assert_eq!(&read[0].id, b"odd ");
+			assert_eq!(read[0].data, vec![1, 2, 3]);
+			assert_eq!(read[1].data, vec![4, 5]);
+			assert!(read_container(&buf, *order, b"FORM", b"TEST").is_err());
+		}
+	}
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::analysis::{self, Stats};
+use crate::loader::{self, Format};
+use crate::multichannel;
+
+/// How much an effect is used across the collection
+#[derive(Debug, Serialize)]
+pub struct EffectUsage {
+	/// Name from ptmf::EFFECT_NAMES
+	pub name: String,
+	/// Number of modules using the effect
+	pub modules: u32,
+	/// Number of pattern cells with the effect
+	pub cells: u64,
+}
+
+/// A file that could not be loaded
+#[derive(Debug, Serialize)]
+pub struct Failure {
+	pub file: String,
+	pub reason: String,
+}
+
+/// Statistics aggregated over many modules
+#[derive(Debug, Serialize)]
+pub struct CollectionStats {
+	pub files: u32,
+	pub modules: u32,
+	/// Number of modules per format, like MOD M.K. or The Player 6.1A
+	pub formats: BTreeMap<String, u32>,
+	/// Number of modules per channel count
+	pub channels: BTreeMap<usize, u32>,
+	/// Indexed like ptmf::EFFECT_NAMES, E-commands are 16-31
+	pub effects: Vec<EffectUsage>,
+	/// Length in bytes of all samples with length > 0
+	pub sample_lengths: Stats,
+	/// Number of samples per length, keyed by the power of two
+	/// the length is rounded up to
+	pub sample_length_histogram: BTreeMap<u32, u32>,
+	pub failures: Vec<Failure>,
+}
+
+impl Default for CollectionStats {
+	fn default() -> CollectionStats {
+		CollectionStats::new()
+	}
+}
+
+impl CollectionStats {
+	pub fn new() -> CollectionStats {
+		CollectionStats {
+			files: 0,
+			modules: 0,
+			formats: BTreeMap::new(),
+			channels: BTreeMap::new(),
+			effects: ptmf::EFFECT_NAMES.iter()
+				.map(|name| EffectUsage{name: name.to_string(), modules: 0, cells: 0})
+				.collect(),
+			sample_lengths: Stats::new(),
+			sample_length_histogram: BTreeMap::new(),
+			failures: Vec::new(),
+		}
+	}
+
+	/// Adds a loaded module with the name of its format
+	pub fn add_module(&mut self, format: &str, module: &ptmf::PTModule) {
+		self.files += 1;
+		self.modules += 1;
+		*self.formats.entry(format.to_string()).or_insert(0) += 1;
+		*self.channels.entry(multichannel::num_channels(module)).or_insert(0) += 1;
+
+		let mut used = [false; 32];
+		for pattern in &module.patterns {
+			for row in &pattern.rows {
+				for channel in &row.channels {
+					if let Some(index) = analysis::effect_index(channel.effect) {
+						self.effects[index].cells += 1;
+						used[index] = true;
+					}
+				}
+			}
+		}
+		for (index, used) in used.iter().enumerate() {
+			if *used {
+				self.effects[index].modules += 1;
+			}
+		}
+
+		for sample in module.sample_info.iter().filter(|si| si.length > 0) {
+			let length = sample.length as u32 * 2;
+			self.sample_lengths.update(length as i32);
+			*self.sample_length_histogram.entry(length.next_power_of_two()).or_insert(0) += 1;
+		}
+	}
+
+	/// Adds a file that could not be loaded
+	pub fn add_failure(&mut self, file: &str, reason: &str) {
+		self.files += 1;
+		self.failures.push(Failure{file: file.to_string(), reason: reason.to_string()});
+	}
+
+	/// Loads the file and adds it as a module or a failure
+	pub fn add_file(&mut self, path: &Path, skip_filesize_check: bool) {
+		let file = path.to_string_lossy();
+		let buf = match fs::read(path) {
+			Ok(buf) => buf,
+			Err(e) => return self.add_failure(&file, &format!("Failed to read file: {}", e)),
+		};
+		let format = match loader::detect(&buf) {
+			Some(format) => format,
+			None => return self.add_failure(&file, "Unknown file format"),
+		};
+		match loader::load_as(&buf, format, skip_filesize_check) {
+			Ok(module) => self.add_module(&format_name(&buf, format), &module),
+			Err(e) => self.add_failure(&file, &format!("{:#}", e)),
+		}
+	}
+
+	/// Finds all files below path, or path itself if it is not a
+	/// directory, sorted by name. Directories that can not be read are
+	/// added as failures and symbolic links to directories are not
+	/// followed.
+	pub fn find_files(&mut self, path: &Path) -> Vec<PathBuf> {
+		let mut files = Vec::new();
+		if path.is_dir() {
+			self.find_files_in(path, &mut files);
+		} else {
+			files.push(path.to_path_buf());
+		}
+		files
+	}
+
+	fn find_files_in(&mut self, dir: &Path, files: &mut Vec<PathBuf>) {
+		let name = dir.to_string_lossy();
+		let entries = match fs::read_dir(dir) {
+			Ok(entries) => entries,
+			Err(e) => return self.add_failure(&name, &format!("Failed to read directory: {}", e)),
+		};
+		let mut paths = Vec::new();
+		for entry in entries {
+			match entry {
+				Ok(entry) => paths.push(entry.path()),
+				Err(e) => self.add_failure(&name, &format!("Failed to read directory: {}", e)),
+			}
+		}
+		paths.sort();
+
+		for path in paths {
+			match fs::symlink_metadata(&path) {
+				Ok(metadata) if metadata.is_dir() => self.find_files_in(&path, files),
+				// A link to a directory could lead back up the tree
+				Ok(metadata) if metadata.file_type().is_symlink() && path.is_dir() => (),
+				Ok(_) => files.push(path),
+				Err(e) => self.add_failure(&path.to_string_lossy(), &format!("Failed to read file: {}", e)),
+			}
+		}
+	}
+
+	/// Computes the averages when all modules are added
+	pub fn done(&mut self) {
+		self.sample_lengths.done();
+	}
+}
+
+/// The format name, with the tag for MOD files
+pub fn format_name(buf: &[u8], format: Format) -> String {
+	match format {
+		Format::Mod => {
+			if buf.len() >= 1084 && multichannel::parse_tag(&buf[1080..1084]).is_some() {
+				format!("MOD {}", String::from_utf8_lossy(&buf[1080..1084]))
+			} else {
+				"MOD 15-sample".to_string()
+			}
+		},
+		_ => format.name().to_string(),
+	}
+}
+
+fn percent(count: u32, total: u32) -> f64 {
+	if total == 0 {
+		0.0
+	} else {
+		count as f64 * 100.0 / total as f64
+	}
+}
+
+/// Writes the statistics as tables
+pub fn write_text(writer: &mut dyn Write, stats: &CollectionStats) -> io::Result<()> {
+	writeln!(writer, "Collection summary")?;
+	writeln!(writer, "\tFiles: {}", stats.files)?;
+	writeln!(writer, "\tModules: {}", stats.modules)?;
+	writeln!(writer, "\tFailures: {}", stats.failures.len())?;
+	writeln!(writer)?;
+
+	writeln!(writer, "Formats")?;
+	for (format, count) in &stats.formats {
+		writeln!(writer, "\t{:<20} {:>8} {:>6.1}%", format, count, percent(*count, stats.modules))?;
+	}
+	writeln!(writer)?;
+
+	writeln!(writer, "Channels")?;
+	for (channels, count) in &stats.channels {
+		writeln!(writer, "\t{:<20} {:>8} {:>6.1}%", channels, count, percent(*count, stats.modules))?;
+	}
+	writeln!(writer)?;
+
+	writeln!(writer, "Effects")?;
+	writeln!(writer, "\t{:<34} {:>8} {:>7} {:>10}", "Effect", "Modules", "", "Cells")?;
+	for effect in &stats.effects {
+		writeln!(writer, "\t{:<34} {:>8} {:>6.1}% {:>10}",
+			effect.name, effect.modules, percent(effect.modules, stats.modules), effect.cells)?;
+	}
+	writeln!(writer)?;
+
+	let lengths = &stats.sample_lengths;
+	writeln!(writer, "Sample lengths")?;
+	if lengths.num_values > 0 {
+		writeln!(writer, "\tSamples: {} min: {} max: {} avg: {} median: {:.1} p90: {:.1} std dev: {:.1}",
+			lengths.num_values, lengths.min, lengths.max, lengths.avg,
+			lengths.median, lengths.p90, lengths.std_dev)?;
+	} else {
+		writeln!(writer, "\tSamples: 0")?;
+	}
+	for (size, count) in &stats.sample_length_histogram {
+		let range = format!("{}-{}", size / 2 + 1, size);
+		writeln!(writer, "\t{:<20} {:>8} {:>6.1}%", range, count, percent(*count, lengths.num_values))?;
+	}
+	writeln!(writer)?;
+
+	writeln!(writer, "Failures")?;
+	for failure in &stats.failures {
+		writeln!(writer, "\t{}: {}", failure.file, failure.reason)?;
+	}
+
+	Ok(())
+}
+
+pub fn write_json(writer: &mut dyn Write, stats: &CollectionStats) -> io::Result<()> {
+	serde_json::to_writer_pretty(&mut *writer, stats)?;
+	writeln!(writer)
+}
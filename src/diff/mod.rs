@@ -0,0 +1,275 @@
+use std::io::{self, Write};
+use serde::Serialize;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::multichannel;
+use crate::note;
+use crate::text;
+
+/// A pattern cell, note is the name of the nearest note
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cell {
+	pub period: u16,
+	pub note: String,
+	pub sample_number: u8,
+	pub effect: u16,
+}
+
+impl Cell {
+	pub fn new(channel: &ptmf::Channel, use_spn: bool) -> Cell {
+		Cell{
+			period: channel.period,
+			note: note::tracker_name(channel.period, use_spn),
+			sample_number: channel.sample_number,
+			effect: channel.effect,
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct PositionDiff {
+	pub position: usize,
+	pub old: u8,
+	pub new: u8,
+}
+
+/// A changed sample header field, values as text
+#[derive(Debug, Serialize)]
+pub struct SampleHeaderDiff {
+	pub number: usize,
+	pub field: String,
+	pub old: String,
+	pub new: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SampleDataDiff {
+	pub number: usize,
+	pub first_offset: usize,
+	/// Bytes that differ, bytes only in one of the samples count as changed
+	pub changed_bytes: usize,
+	pub old_length: usize,
+	pub new_length: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CellDiff {
+	pub pattern: usize,
+	pub row: usize,
+	pub channel: usize,
+	pub old: Cell,
+	pub new: Cell,
+}
+
+/// Differences between two modules. Sample numbers are 1-31 and channels
+/// start at 1, like in the rest of the tool, patterns and rows at 0.
+#[derive(Debug, Default, Serialize)]
+pub struct ModuleDiff {
+	pub name: Option<(String, String)>,
+	pub length: Option<(u8, u8)>,
+	pub positions: Vec<PositionDiff>,
+	pub num_patterns: Option<(usize, usize)>,
+	pub num_channels: Option<(usize, usize)>,
+	pub sample_headers: Vec<SampleHeaderDiff>,
+	pub sample_data: Vec<SampleDataDiff>,
+	pub cells: Vec<CellDiff>,
+}
+
+impl ModuleDiff {
+	pub fn is_empty(&self) -> bool {
+		self.name.is_none() &&
+			self.length.is_none() &&
+			self.positions.is_empty() &&
+			self.num_patterns.is_none() &&
+			self.num_channels.is_none() &&
+			self.sample_headers.is_empty() &&
+			self.sample_data.is_empty() &&
+			self.cells.is_empty()
+	}
+}
+
+fn diff_sample_data(number: usize, old: &[u8], new: &[u8]) -> Option<SampleDataDiff> {
+	let common = old.len().min(new.len());
+	let mut first_offset = None;
+	let mut changed_bytes = old.len().max(new.len()) - common;
+	for i in 0..common {
+		if old[i] != new[i] {
+			first_offset.get_or_insert(i);
+			changed_bytes += 1;
+		}
+	}
+	if changed_bytes == 0 {
+		return None;
+	}
+
+	Some(SampleDataDiff{
+		number,
+		first_offset: first_offset.unwrap_or(common),
+		changed_bytes,
+		old_length: old.len(),
+		new_length: new.len(),
+	})
+}
+
+/// Compares two modules. Only positions within the song length are compared,
+/// and only patterns and channels that are in both modules.
+pub fn diff(old: &ptmf::PTModule, new: &ptmf::PTModule, use_spn: bool) -> ModuleDiff {
+	let mut result = ModuleDiff::default();
+
+	if old.name != new.name {
+		result.name = Some((old.name.clone(), new.name.clone()));
+	}
+	if old.length != new.length {
+		result.length = Some((old.length, new.length));
+	}
+	let num_positions = (old.length.max(new.length) as usize)
+		.min(old.positions.data.len())
+		.min(new.positions.data.len());
+	for position in 0..num_positions {
+		let a = old.positions.data[position];
+		let b = new.positions.data[position];
+		if a != b {
+			result.positions.push(PositionDiff{position, old: a, new: b});
+		}
+	}
+	if old.patterns.len() != new.patterns.len() {
+		result.num_patterns = Some((old.patterns.len(), new.patterns.len()));
+	}
+	let num_channels = (multichannel::num_channels(old), multichannel::num_channels(new));
+	if num_channels.0 != num_channels.1 {
+		result.num_channels = Some(num_channels);
+	}
+
+	for (i, (a, b)) in old.sample_info.iter().zip(new.sample_info.iter()).enumerate() {
+		let number = i + 1;
+		let fields = [
+			("name", a.name.clone(), b.name.clone()),
+			("length", (a.length as u32 * 2).to_string(), (b.length as u32 * 2).to_string()),
+			("finetune", note::finetune_to_signed(a.finetune).to_string(), note::finetune_to_signed(b.finetune).to_string()),
+			("volume", a.volume.to_string(), b.volume.to_string()),
+			("repeat_start", (a.repeat_start as u32 * 2).to_string(), (b.repeat_start as u32 * 2).to_string()),
+			("repeat_length", (a.repeat_length as u32 * 2).to_string(), (b.repeat_length as u32 * 2).to_string()),
+		];
+		for (field, a, b) in fields.iter() {
+			if a != b {
+				result.sample_headers.push(SampleHeaderDiff{
+					number,
+					field: field.to_string(),
+					old: a.clone(),
+					new: b.clone(),
+				});
+			}
+		}
+		if let Some(data) = diff_sample_data(number, &a.data, &b.data) {
+			result.sample_data.push(data);
+		}
+	}
+
+	for (pattern_no, (a, b)) in old.patterns.iter().zip(new.patterns.iter()).enumerate() {
+		for (row_no, (row_a, row_b)) in a.rows.iter().zip(b.rows.iter()).enumerate() {
+			for (channel_no, (ca, cb)) in row_a.channels.iter().zip(row_b.channels.iter()).enumerate() {
+				if ca.period != cb.period ||
+					ca.sample_number != cb.sample_number ||
+					ca.effect != cb.effect {
+					result.cells.push(CellDiff{
+						pattern: pattern_no,
+						row: row_no,
+						channel: channel_no + 1,
+						old: Cell::new(ca, use_spn),
+						new: Cell::new(cb, use_spn),
+					});
+				}
+			}
+		}
+	}
+
+	result
+}
+
+fn format_cell(cell: &Cell, use_spn: bool) -> String {
+	let channel = ptmf::Channel{period: cell.period, sample_number: cell.sample_number, effect: cell.effect};
+	text::format_channel(&channel, use_spn)
+}
+
+/// Writes the differences as text, one line per difference
+pub fn write_text(writer: &mut dyn Write, diff: &ModuleDiff, use_spn: bool) -> io::Result<()> {
+	if diff.is_empty() {
+		return writeln!(writer, "No differences");
+	}
+	if let Some((a, b)) = &diff.name {
+		writeln!(writer, "Name: '{}' -> '{}'", a, b)?;
+	}
+	if let Some((a, b)) = diff.length {
+		writeln!(writer, "Length: {} -> {}", a, b)?;
+	}
+	for p in &diff.positions {
+		writeln!(writer, "Position {}: {} -> {}", p.position, p.old, p.new)?;
+	}
+	if let Some((a, b)) = diff.num_patterns {
+		writeln!(writer, "Number of patterns: {} -> {}", a, b)?;
+	}
+	if let Some((a, b)) = diff.num_channels {
+		writeln!(writer, "Number of channels: {} -> {}", a, b)?;
+	}
+	for s in &diff.sample_headers {
+		writeln!(writer, "Sample {} {}: '{}' -> '{}'", s.number, s.field, s.old, s.new)?;
+	}
+	for s in &diff.sample_data {
+		writeln!(writer, "Sample {} data: {} bytes changed, first at offset {}, length {} -> {}",
+			s.number, s.changed_bytes, s.first_offset, s.old_length, s.new_length)?;
+	}
+	for c in &diff.cells {
+		writeln!(writer, "Pattern {} row {} channel {}: {} -> {}",
+			c.pattern, c.row, c.channel, format_cell(&c.old, use_spn), format_cell(&c.new, use_spn))?;
+	}
+
+	Ok(())
+}
+
+pub fn write_json(writer: &mut dyn Write, diff: &ModuleDiff) -> io::Result<()> {
+	serde_json::to_writer_pretty(&mut *writer, diff)?;
+	writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil;
+
+	#[test]
+	fn length_above_128_does_not_panic() {
+		let old = testutil::module(1);
+		let mut new = testutil::module(1);
+		new.length = 200;
+		new.positions.data[127] = 1;
+
+		let diff = diff(&old, &new, false);
+		assert_eq!(diff.length, Some((1, 200)));
+		assert_eq!(diff.positions.len(), 1);
+		assert_eq!(diff.positions[0].position, 127);
+	}
+
+	#[test]
+	fn channel_count_difference_is_reported() {
+		let old = testutil::module(1);
+		let mut new = testutil::module(1);
+		for row in &mut new.patterns[0].rows {
+			let extra = row.channels.clone();
+			row.channels.extend(extra);
+		}
+		testutil::set_note(&mut new, 0, 2, 0, 428, 1);
+
+		let diff = diff(&old, &new, false);
+		assert_eq!(diff.num_channels, Some((4, 8)));
+		assert_eq!(diff.cells.len(), 1);
+		assert_eq!((diff.cells[0].row, diff.cells[0].channel), (2, 1));
+
+		let mut text = Vec::new();
+		write_text(&mut text, &diff, false).unwrap();
+		let text = String::from_utf8(text).unwrap();
+		assert!(text.contains("Number of channels: 4 -> 8\n"));
+		assert!(text.contains("Pattern 0 row 2 channel 1: "));
+	}
+}
@@ -0,0 +1,189 @@
+use std::io::{self, Write};
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::note;
+use crate::sample::{self, Sample};
+
+pub use crate::chunk::Chunk;
+use crate::chunk::{self, ByteOrder};
+
+/// Writes an IFF FORM of form_type containing chunks
+pub fn write_form(writer: &mut dyn Write, form_type: &[u8; 4], chunks: &[Chunk]) -> io::Result<()> {
+	chunk::write_container(writer, ByteOrder::Big, b"FORM", form_type, chunks)
+}
+
+/// Creates a VHDR chunk.
+/// volume is 0-64 like in ProTracker and is stored as 16.16 fixed point.
+fn vhdr_chunk(one_shot: u32, repeat: u32, sample_rate: u32, volume: u8) -> Chunk {
+	let mut data = Vec::new();
+	data.extend_from_slice(&one_shot.to_be_bytes());
+	data.extend_from_slice(&repeat.to_be_bytes());
+	data.extend_from_slice(&0u32.to_be_bytes()); // samplesPerHiCycle
+	data.extend_from_slice(&(sample_rate.min(u16::MAX as u32) as u16).to_be_bytes());
+	data.push(1); // ctOctave
+	data.push(0); // sCompression, none
+	data.extend_from_slice(&(volume.min(64) as u32 * 0x10000 / 64).to_be_bytes());
+	Chunk::new(b"VHDR", data)
+}
+
+/// The one shot and repeat part of a sample in bytes
+fn parts(sample: &ptmf::SampleInfo) -> (u32, u32) {
+	if sample.repeat_length > 1 {
+		let len = sample.data.len() as u32;
+		let start = (sample.repeat_start as u32 * 2).min(len);
+		let end = (start + sample.repeat_length as u32 * 2).min(len);
+		(start, end - start)
+	} else {
+		(sample.data.len() as u32, 0)
+	}
+}
+
+/// Number of bytes after the loop end, which write_sample drops
+pub fn bytes_after_loop(sample: &ptmf::SampleInfo) -> usize {
+	let (one_shot, repeat) = parts(sample);
+	sample.data.len() - (one_shot + repeat) as usize
+}
+
+/// Writes a sample as IFF 8SVX.
+/// The part before repeat start is stored as oneShotHiSamples and
+/// the repeat part as repeatHiSamples. 8SVX has no room for data after
+/// the loop, it is never played by ProTracker and is not written,
+/// see bytes_after_loop.
+pub fn write_sample(writer: &mut dyn Write, sample: &ptmf::SampleInfo, sample_rate: u32) -> io::Result<()> {
+	let (one_shot, repeat) = parts(sample);
+	let body = sample.data[0..(one_shot + repeat) as usize].to_vec();
+	let annotation = format!("Finetune: {}", note::finetune_to_signed(sample.finetune));
+
+	let chunks = [
+		vhdr_chunk(one_shot, repeat, sample_rate, sample.volume),
+		Chunk::new(b"NAME", sample.name.as_bytes().to_vec()),
+		Chunk::new(b"ANNO", annotation.into_bytes()),
+		Chunk::new(b"BODY", body),
+	];
+
+	write_form(writer, b"8SVX", &chunks)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+	ByteOrder::Big.read_u16(buf, offset)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+	ByteOrder::Big.read_u32(buf, offset)
+}
+
+/// Splits an IFF FORM of form_type into chunks
+pub fn read_form(buf: &[u8], form_type: &[u8; 4]) -> Result<Vec<Chunk>> {
+	chunk::read_container(buf, ByteOrder::Big, b"FORM", form_type)
+}
+
+/// Unpacks Fibonacci delta compressed data
+fn unpack_fibonacci(data: &[u8]) -> Vec<u8> {
+	const CODE_TO_DELTA: [i8; 16] = [-34, -21, -13, -8, -5, -3, -2, -1, 0, 1, 2, 3, 5, 8, 13, 21];
+
+	let mut result = Vec::new();
+	if data.len() < 2 {
+		return result;
+	}
+	// First byte is padding, second is the initial value
+	let mut x = data[1] as i8;
+	for b in &data[2..] {
+		x = x.wrapping_add(CODE_TO_DELTA[(b >> 4) as usize]);
+		result.push(x as u8);
+		x = x.wrapping_add(CODE_TO_DELTA[(b & 0x0f) as usize]);
+		result.push(x as u8);
+	}
+
+	result
+}
+
+/// Reads an IFF 8SVX file, uncompressed or Fibonacci delta compressed.
+/// Only the first octave is used. Loop is read from VHDR, where the
+/// repeat part follows the one shot part, and the name from NAME.
+pub fn read_sample(buf: &[u8]) -> Result<Sample> {
+	let chunks = read_form(buf, b"8SVX")?;
+	let mut result = Sample::default();
+	let mut vhdr = None;
+
+	for chunk in &chunks {
+		let data = &chunk.data;
+		match &chunk.id {
+			b"VHDR" if data.len() >= 20 => {
+				let one_shot = read_u32(data, 0) as usize;
+				let repeat = read_u32(data, 4) as usize;
+				let compression = data[15];
+				let sample_rate = read_u16(data, 12) as u32;
+				if sample_rate == 0 {
+					return Err(anyhow!("Invalid sample rate 0 Hz"));
+				}
+				result.sample_rate = Some(sample_rate);
+				result.volume = Some(((read_u32(data, 16) as u64 * 64 + 0x8000) / 0x10000).min(64) as u8);
+				vhdr = Some((one_shot, repeat, compression));
+			},
+			b"NAME" => {
+				result.name = Some(String::from_utf8_lossy(data).trim_end_matches('\0').to_string());
+			},
+			b"ANNO" => {
+				sample::parse_comment(&String::from_utf8_lossy(data), &mut result);
+			},
+			_ => (),
+		}
+	}
+
+	let (one_shot, repeat, compression) = match vhdr {
+		Some(vhdr) => vhdr,
+		None => return Err(anyhow!("8SVX file has no VHDR chunk")),
+	};
+	let body = match chunks.iter().find(|c| &c.id == b"BODY") {
+		Some(chunk) => &chunk.data,
+		None => return Err(anyhow!("8SVX file has no BODY chunk")),
+	};
+	let body = match compression {
+		0 => body.clone(),
+		1 => unpack_fibonacci(body),
+		_ => return Err(anyhow!("Unsupported 8SVX compression {}", compression)),
+	};
+
+	// Only the first, highest, octave
+	let len = if one_shot + repeat > 0 {
+		(one_shot + repeat).min(body.len())
+	} else {
+		body.len()
+	};
+	result.data = body[0..len].iter().map(|b| *b as i8 as f32 / 128.0).collect();
+	if repeat > 0 && one_shot < len {
+		result.sample_loop = Some((one_shot, len));
+	}
+
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil;
+
+	#[test]
+	fn data_after_the_loop_is_dropped() {
+		let mut module = testutil::module(1);
+		testutil::set_square(&mut module, 1, 8);
+		let si = &mut module.sample_info[0];
+		si.repeat_start = 2;
+		si.repeat_length = 4;
+		assert_eq!(bytes_after_loop(si), 4);
+
+		let mut buf = Vec::new();
+		write_sample(&mut buf, si, 8363).unwrap();
+		let sample = read_sample(&buf).unwrap();
+		assert_eq!(sample.data.len(), 12);
+		assert_eq!(sample.sample_loop, Some((4, 12)));
+		assert_eq!(sample.sample_rate, Some(8363));
+
+		si.repeat_start = 0;
+		si.repeat_length = 1;
+		assert_eq!(bytes_after_loop(si), 0);
+	}
+}
@@ -1,2 +1,23 @@
+pub mod pretty;
+pub mod note;
+pub mod chunk;
+pub mod iff;
+pub mod sample;
+pub mod sequencer;
+pub mod reachability;
+pub mod analysis;
+pub mod usecode;
+pub mod collection;
+pub mod replayer;
+pub mod timeline;
+pub mod text;
+pub mod diff;
+pub mod merge3;
+pub mod transform;
+pub mod multichannel;
+pub mod soundtracker;
+pub mod loader;
+pub mod wav;
 
-pub mod pretty;
\ No newline at end of file
+#[cfg(test)]
+mod testutil;
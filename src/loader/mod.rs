@@ -0,0 +1,131 @@
+use std::io::Read;
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::multichannel;
+use crate::soundtracker;
+
+/// Input formats that can be loaded into a PTModule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+	/// ProTracker and compatible, including multichannel and 15-sample modules
+	Mod,
+	/// The Player 6.1A
+	P61,
+	/// JSON written by mod2json
+	Json,
+}
+
+impl Format {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Format::Mod => "MOD",
+			Format::P61 => "The Player 6.1A",
+			Format::Json => "JSON",
+		}
+	}
+}
+
+/// The optional signature at the start of a P61 file
+const P61_SIGNATURE: &[u8; 4] = b"P61A";
+
+/// Checks if the P61 header, without signature, has sane values.
+/// It starts with the offset to the sample data, the number of
+/// patterns and the number of samples with flags in the upper bits.
+fn looks_like_p61(buf: &[u8]) -> bool {
+	if buf.len() < 4 {
+		return false;
+	}
+	let sample_offset = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+	let num_patterns = buf[2] as usize;
+	let num_samples = (buf[3] & 0x3f) as usize;
+
+	num_patterns > 0 &&
+		num_samples <= 31 &&
+		sample_offset >= 4 + num_samples * 6 &&
+		sample_offset <= buf.len()
+}
+
+/// Detects the format from the file contents. MOD tags like M.K.,
+/// M!K!, FLT4 and xCHN, the P61A signature and JSON are recognized
+/// directly, 15-sample modules and P61 files without signature from
+/// their headers.
+pub fn detect(buf: &[u8]) -> Option<Format> {
+	// The song name of a MOD can start with {, check the tag first
+	if buf.len() >= 1084 && multichannel::parse_tag(&buf[1080..1084]).is_some() {
+		return Some(Format::Mod);
+	}
+	let first = buf.iter().find(|b| !b.is_ascii_whitespace());
+	if first == Some(&b'{') {
+		return Some(Format::Json);
+	}
+	if buf.starts_with(P61_SIGNATURE) {
+		return Some(Format::P61);
+	}
+	if soundtracker::is_soundtracker(buf) {
+		return Some(Format::Mod);
+	}
+	if looks_like_p61(buf) {
+		return Some(Format::P61);
+	}
+
+	None
+}
+
+/// Loads a module of the given format
+pub fn load_as(buf: &[u8], format: Format, skip_filesize_check: bool) -> Result<ptmf::PTModule> {
+	load_as_with_warnings(buf, format, skip_filesize_check).map(|(module, _)| module)
+}
+
+/// Like load_as, also returns warnings about things that could
+/// not be converted
+pub fn load_as_with_warnings(buf: &[u8], format: Format, skip_filesize_check: bool) -> Result<(ptmf::PTModule, Vec<String>)> {
+	match format {
+		Format::Mod => multichannel::read_mod_with_warnings(&mut &buf[..], skip_filesize_check),
+		Format::P61 => {
+			let data = if buf.starts_with(P61_SIGNATURE) { &buf[4..] } else { buf };
+			ptmf::read_p61(&mut &data[..])
+				.map(|module| (module, Vec::new()))
+				.map_err(|e| anyhow!("{:?}", e))
+		},
+		Format::Json => Ok((serde_json::from_slice(buf)?, Vec::new())),
+	}
+}
+
+/// Detects the format and loads a module
+pub fn load(buf: &[u8], skip_filesize_check: bool) -> Result<ptmf::PTModule> {
+	match detect(buf) {
+		Some(format) => load_as(buf, format, skip_filesize_check),
+		None => Err(anyhow!("Unknown file format")),
+	}
+}
+
+/// Reads all data from reader, detects the format and loads a module
+pub fn read(reader: &mut dyn Read, skip_filesize_check: bool) -> Result<ptmf::PTModule> {
+	read_with_warnings(reader, skip_filesize_check).map(|(module, _)| module)
+}
+
+/// Like read, also returns warnings about things that could
+/// not be converted
+pub fn read_with_warnings(reader: &mut dyn Read, skip_filesize_check: bool) -> Result<(ptmf::PTModule, Vec<String>)> {
+	let mut buf = Vec::new();
+	reader.read_to_end(&mut buf)?;
+	match detect(&buf) {
+		Some(format) => load_as_with_warnings(&buf, format, skip_filesize_check),
+		None => Err(anyhow!("Unknown file format")),
+	}
+}
+
+/// Like read, with the file size check
+pub fn read_any(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+	read(reader, false)
+}
+
+/// Like read, but always reads The Player 6.1A
+pub fn read_p61(reader: &mut dyn Read) -> Result<ptmf::PTModule> {
+	let mut buf = Vec::new();
+	reader.read_to_end(&mut buf)?;
+	load_as(&buf, Format::P61, false)
+}
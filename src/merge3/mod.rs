@@ -0,0 +1,260 @@
+use std::io::{self, Write};
+use serde::Serialize;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::text;
+
+/// Where a conflict is. Sample numbers are 1-31 and channels
+/// start at 1, like in the rest of the tool, patterns and rows at 0.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Location {
+	Name,
+	Length,
+	Position{position: usize},
+	Sample{number: usize, field: String},
+	Cell{pattern: usize, row: usize, channel: usize},
+}
+
+/// A value changed differently in ours and theirs, values as text.
+/// The merged module has the value from ours.
+#[derive(Debug, Serialize)]
+pub struct Conflict {
+	pub location: Location,
+	pub base: String,
+	pub ours: String,
+	pub theirs: String,
+}
+
+/// Returns the merged value, or None if both sides changed it differently
+fn merge_value<T: PartialEq + Clone>(base: &T, ours: &T, theirs: &T) -> Option<T> {
+	if ours == theirs || theirs == base {
+		Some(ours.clone())
+	} else if ours == base {
+		Some(theirs.clone())
+	} else {
+		None
+	}
+}
+
+type Cell = (u16, u8, u16);
+
+fn get_cell(module: &ptmf::PTModule, pattern: usize, row: usize, channel: usize) -> Cell {
+	module.patterns.get(pattern)
+		.and_then(|p| p.rows.get(row))
+		.and_then(|r| r.channels.get(channel))
+		.map(|c| (c.period, c.sample_number, c.effect))
+		.unwrap_or((0, 0, 0))
+}
+
+fn format_cell(cell: &Cell) -> String {
+	let channel = ptmf::Channel{period: cell.0, sample_number: cell.1, effect: cell.2};
+	text::format_channel(&channel, false)
+}
+
+/// Merges the changes from base to theirs into ours, song name, length,
+/// positions, sample headers, sample data and pattern cells one by one.
+/// Values changed differently on both sides are kept as in ours and
+/// returned as conflicts. Missing patterns are treated as empty.
+pub fn merge(base: &ptmf::PTModule, ours: &mut ptmf::PTModule, theirs: &ptmf::PTModule) -> Vec<Conflict> {
+	let mut conflicts = Vec::new();
+
+	match merge_value(&base.name, &ours.name, &theirs.name) {
+		Some(name) => ours.name = name,
+		None => conflicts.push(Conflict{
+			location: Location::Name,
+			base: base.name.clone(),
+			ours: ours.name.clone(),
+			theirs: theirs.name.clone(),
+		}),
+	}
+	match merge_value(&base.length, &ours.length, &theirs.length) {
+		Some(length) => ours.length = length,
+		None => conflicts.push(Conflict{
+			location: Location::Length,
+			base: base.length.to_string(),
+			ours: ours.length.to_string(),
+			theirs: theirs.length.to_string(),
+		}),
+	}
+	for position in 0..ours.positions.data.len() {
+		let (b, o, t) = (base.positions.data[position], ours.positions.data[position], theirs.positions.data[position]);
+		match merge_value(&b, &o, &t) {
+			Some(pattern) => ours.positions.data[position] = pattern,
+			None => conflicts.push(Conflict{
+				location: Location::Position{position},
+				base: b.to_string(),
+				ours: o.to_string(),
+				theirs: t.to_string(),
+			}),
+		}
+	}
+
+	for i in 0..ours.sample_info.len() {
+		let (b, t) = match (base.sample_info.get(i), theirs.sample_info.get(i)) {
+			(Some(b), Some(t)) => (b, t),
+			_ => continue,
+		};
+		let o = &mut ours.sample_info[i];
+		let mut conflict = |field: &str, base: String, ours: String, theirs: String| {
+			conflicts.push(Conflict{
+				location: Location::Sample{number: i + 1, field: field.to_string()},
+				base, ours, theirs,
+			});
+		};
+
+		match merge_value(&b.name, &o.name, &t.name) {
+			Some(name) => o.name = name,
+			None => conflict("name", b.name.clone(), o.name.clone(), t.name.clone()),
+		}
+		match merge_value(&b.finetune, &o.finetune, &t.finetune) {
+			Some(finetune) => o.finetune = finetune,
+			None => conflict("finetune", b.finetune.to_string(), o.finetune.to_string(), t.finetune.to_string()),
+		}
+		match merge_value(&b.volume, &o.volume, &t.volume) {
+			Some(volume) => o.volume = volume,
+			None => conflict("volume", b.volume.to_string(), o.volume.to_string(), t.volume.to_string()),
+		}
+		// The loop is merged as one value, start and length belong together
+		let (bl, ol, tl) = ((b.repeat_start, b.repeat_length), (o.repeat_start, o.repeat_length), (t.repeat_start, t.repeat_length));
+		match merge_value(&bl, &ol, &tl) {
+			Some((start, length)) => {
+				o.repeat_start = start;
+				o.repeat_length = length;
+			},
+			None => conflict("loop", format!("{:?}", bl), format!("{:?}", ol), format!("{:?}", tl)),
+		}
+		// Data and length belong together
+		if o.data != t.data {
+			if o.data == b.data {
+				o.data = t.data.clone();
+				o.length = t.length;
+			} else if t.data != b.data {
+				conflict("data", format!("{} bytes", b.data.len()), format!("{} bytes", o.data.len()), format!("{} bytes", t.data.len()));
+			}
+		}
+		// The loop and the data are merged independently,
+		// the loop from one side may not fit the data from the other
+		if o.repeat_length > 1 && o.repeat_start as usize + o.repeat_length as usize > o.length as usize {
+			let describe = |si: &ptmf::SampleInfo| format!("loop {:?} length {}", (si.repeat_start, si.repeat_length), si.length);
+			let merged = describe(o);
+			conflict("loop", describe(b), merged, describe(t));
+		}
+	}
+
+	// Patterns only in theirs are added
+	for pattern_no in ours.patterns.len()..theirs.patterns.len() {
+		let mut pattern = theirs.patterns[pattern_no].clone();
+		for row in &mut pattern.rows {
+			for channel in &mut row.channels {
+				channel.period = 0;
+				channel.sample_number = 0;
+				channel.effect = 0;
+			}
+		}
+		ours.patterns.push(pattern);
+	}
+
+	for pattern_no in 0..ours.patterns.len() {
+		for row_no in 0..ours.patterns[pattern_no].rows.len() {
+			for channel_no in 0..ours.patterns[pattern_no].rows[row_no].channels.len() {
+				let b = get_cell(base, pattern_no, row_no, channel_no);
+				let o = get_cell(ours, pattern_no, row_no, channel_no);
+				let t = get_cell(theirs, pattern_no, row_no, channel_no);
+				match merge_value(&b, &o, &t) {
+					Some((period, sample_number, effect)) => {
+						ours.patterns[pattern_no].rows[row_no].channels[channel_no] =
+							ptmf::Channel{period, sample_number, effect};
+					},
+					None => conflicts.push(Conflict{
+						location: Location::Cell{pattern: pattern_no, row: row_no, channel: channel_no + 1},
+						base: format_cell(&b),
+						ours: format_cell(&o),
+						theirs: format_cell(&t),
+					}),
+				}
+			}
+		}
+	}
+
+	// The number of patterns in a MOD is given by the play order
+	let num_patterns = (0..ours.positions.data.len())
+		.map(|p| ours.positions.data[p] as usize + 1)
+		.max()
+		.unwrap_or(1);
+	ours.patterns.truncate(num_patterns);
+
+	conflicts
+}
+
+pub fn write_conflicts(writer: &mut dyn Write, conflicts: &[Conflict]) -> io::Result<()> {
+	serde_json::to_writer_pretty(&mut *writer, conflicts)?;
+	writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil;
+
+	fn modules() -> (ptmf::PTModule, ptmf::PTModule, ptmf::PTModule) {
+		let square = || {
+			let mut module = testutil::module(1);
+			testutil::set_square(&mut module, 1, 8);
+			module.sample_info[0].repeat_length = 2;
+			module
+		};
+		(square(), square(), square())
+	}
+
+	/// Cuts the data of sample 1 to length words, keeping the loop
+	fn shorten(module: &mut ptmf::PTModule, length: u16) {
+		let si = &mut module.sample_info[0];
+		si.data.truncate(length as usize * 2);
+		si.length = length;
+	}
+
+	#[test]
+	fn loop_beyond_merged_data_is_a_conflict() {
+		let (base, mut ours, mut theirs) = modules();
+		// Ours shortens the sample, theirs moves the loop
+		shorten(&mut ours, 4);
+		theirs.sample_info[0].repeat_start = 2;
+		theirs.sample_info[0].repeat_length = 6;
+
+		let conflicts = merge(&base, &mut ours, &theirs);
+		assert_eq!(conflicts.len(), 1);
+		match &conflicts[0].location {
+			Location::Sample{number, field} => assert_eq!((*number, field.as_str()), (1, "loop")),
+			location => panic!("Unexpected conflict at {:?}", location),
+		}
+		assert_eq!(conflicts[0].ours, "loop (2, 6) length 4");
+	}
+
+	#[test]
+	fn loop_inside_merged_data_is_merged() {
+		let (base, mut ours, mut theirs) = modules();
+		shorten(&mut ours, 4);
+		theirs.sample_info[0].repeat_start = 2;
+		theirs.sample_info[0].repeat_length = 2;
+
+		assert!(merge(&base, &mut ours, &theirs).is_empty());
+		assert_eq!((ours.sample_info[0].repeat_start, ours.sample_info[0].repeat_length), (2, 2));
+	}
+
+	#[test]
+	fn cell_conflict_channels_start_at_1() {
+		let (base, mut ours, mut theirs) = modules();
+		testutil::set_note(&mut ours, 0, 3, 0, 428, 1);
+		testutil::set_note(&mut theirs, 0, 3, 0, 214, 1);
+
+		let conflicts = merge(&base, &mut ours, &theirs);
+		assert_eq!(conflicts.len(), 1);
+		match conflicts[0].location {
+			Location::Cell{pattern, row, channel} => assert_eq!((pattern, row, channel), (0, 3, 1)),
+			ref location => panic!("Unexpected conflict at {:?}", location),
+		}
+	}
+}
@@ -0,0 +1,308 @@
+use std::io::{Read, Write};
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::soundtracker;
+
+/// Offset of the format tag, like M.K., in a MOD file
+const TAG_OFFSET: usize = 1080;
+/// Offset of the position list
+const POSITIONS_OFFSET: usize = 952;
+const ROWS: usize = 64;
+
+/// How patterns are stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+	/// One row after the other, all channels in each row
+	Normal,
+	/// StarTrekker FLT8, each pattern is stored as two four channel
+	/// patterns, channel 1-4 and 5-8. Positions are doubled.
+	Flt8,
+}
+
+/// Number of channels and pattern layout from the format tag,
+/// None for unknown tags, like in 15-sample modules
+pub fn parse_tag(tag: &[u8]) -> Option<(usize, Layout)> {
+	match tag {
+		b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => Some((4, Layout::Normal)),
+		b"FLT8" => Some((8, Layout::Flt8)),
+		b"CD81" | b"OKTA" | b"OCTA" => Some((8, Layout::Normal)),
+		[n, b'C', b'H', b'N'] if n.is_ascii_digit() && *n > b'0' => Some(((n - b'0') as usize, Layout::Normal)),
+		[a, b, b'C', b'H'] if a.is_ascii_digit() && b.is_ascii_digit() => {
+			let channels = ((a - b'0') * 10 + (b - b'0')) as usize;
+			if channels > 0 && channels <= 32 {
+				Some((channels, Layout::Normal))
+			} else {
+				None
+			}
+		},
+		_ => None,
+	}
+}
+
+/// The tag written for a number of channels
+pub fn tag_for(num_channels: usize) -> Result<[u8; 4]> {
+	match num_channels {
+		4 => Ok(*b"M.K."),
+		1..=9 => Ok([b'0' + num_channels as u8, b'C', b'H', b'N']),
+		10..=32 => Ok([b'0' + (num_channels / 10) as u8, b'0' + (num_channels % 10) as u8, b'C', b'H']),
+		_ => Err(anyhow!("Unable to write a module with {} channels", num_channels)),
+	}
+}
+
+pub fn num_channels(module: &ptmf::PTModule) -> usize {
+	module.patterns.first()
+		.and_then(|p| p.rows.first())
+		.map(|r| r.channels.len())
+		.unwrap_or(4)
+}
+
+fn read_channel(c: &[u8]) -> ptmf::Channel {
+	ptmf::Channel{
+		period: (((c[0] & 0x0f) as u16) << 8) | c[1] as u16,
+		sample_number: (c[0] & 0xf0) | (c[2] >> 4),
+		effect: (((c[2] & 0x0f) as u16) << 8) | c[3] as u16,
+	}
+}
+
+fn write_channel(out: &mut Vec<u8>, c: &ptmf::Channel) {
+	out.push((c.sample_number & 0xf0) | ((c.period >> 8) as u8 & 0x0f));
+	out.push(c.period as u8);
+	out.push(((c.sample_number & 0x0f) << 4) | ((c.effect >> 8) as u8 & 0x0f));
+	out.push(c.effect as u8);
+}
+
+/// Reads a MOD with any of the common tags, M.K., M!K!, FLT4, 4CHN,
+/// xCHN, xxCH, FLT8, CD81 and OKTA, or a 15-sample Soundtracker module.
+/// Four channel modules, and modules without a known tag, are read by
+/// ptmf::read_mod. For other modules ptmf::read_mod reads the samples
+/// and the patterns are read here.
+pub fn read_mod(reader: &mut dyn Read, skip_filesize_check: bool) -> Result<ptmf::PTModule> {
+	read_mod_with_warnings(reader, skip_filesize_check).map(|(module, _)| module)
+}
+
+/// Like read_mod, also returns warnings about things that could
+/// not be converted
+pub fn read_mod_with_warnings(reader: &mut dyn Read, skip_filesize_check: bool) -> Result<(ptmf::PTModule, Vec<String>)> {
+	let mut warnings = Vec::new();
+	let mut buf = Vec::new();
+	reader.read_to_end(&mut buf)?;
+
+	let format = if buf.len() >= TAG_OFFSET + 4 {
+		parse_tag(&buf[TAG_OFFSET..TAG_OFFSET + 4])
+	} else {
+		None
+	};
+	let (num_channels, layout) = match format {
+		Some(format) => format,
+		None if soundtracker::is_soundtracker(&buf) => {
+			let converted = soundtracker::to_protracker(&buf)?;
+			warnings.extend(converted.warnings);
+			let mut module = ptmf::read_mod(&mut &converted.data[..], skip_filesize_check)
+				.map_err(|e| anyhow!("{:?}", e))?;
+			if !soundtracker::apply_tempo(&mut module, converted.tempo_byte) {
+				warnings.push(format!("No free effect on the first row to set the Soundtracker tempo {} BPM",
+					soundtracker::tempo(converted.tempo_byte).unwrap_or(125)));
+			}
+			return Ok((module, warnings));
+		},
+		None => return ptmf::read_mod(&mut &buf[..], skip_filesize_check)
+			.map(|module| (module, warnings))
+			.map_err(|e| anyhow!("{:?}", e)),
+	};
+	if num_channels == 4 {
+		buf[TAG_OFFSET..TAG_OFFSET + 4].copy_from_slice(b"M.K.");
+		return ptmf::read_mod(&mut &buf[..], skip_filesize_check)
+			.map(|module| (module, warnings))
+			.map_err(|e| anyhow!("{:?}", e));
+	}
+
+	let mut positions = buf[POSITIONS_OFFSET..TAG_OFFSET].to_vec();
+	if layout == Layout::Flt8 {
+		for pos in positions.iter_mut() {
+			*pos /= 2;
+		}
+	}
+	let num_patterns = *positions.iter().max().unwrap_or(&0) as usize + 1;
+	let pattern_size = ROWS * num_channels * 4;
+	let samples_start = TAG_OFFSET + 4 + num_patterns * pattern_size;
+	if buf.len() < samples_start {
+		return Err(anyhow!("File is too short for {} patterns with {} channels", num_patterns, num_channels));
+	}
+
+	let mut patterns = Vec::with_capacity(num_patterns);
+	for pattern_no in 0..num_patterns {
+		let data = &buf[TAG_OFFSET + 4 + pattern_no * pattern_size..];
+		let mut rows = Vec::with_capacity(ROWS);
+		for row_no in 0..ROWS {
+			let mut channels = Vec::with_capacity(num_channels);
+			for channel_no in 0..num_channels {
+				let offset = match layout {
+					Layout::Normal => (row_no * num_channels + channel_no) * 4,
+					// Two four channel patterns after each other
+					Layout::Flt8 => (channel_no / 4) * ROWS * 16 + row_no * 16 + (channel_no % 4) * 4,
+				};
+				channels.push(read_channel(&data[offset..offset + 4]));
+			}
+			rows.push(ptmf::Row{channels});
+		}
+		patterns.push(ptmf::Pattern{rows});
+	}
+
+	// Let ptmf read the header and samples from a four channel
+	// module with one empty pattern
+	let mut four_channel = buf[0..POSITIONS_OFFSET].to_vec();
+	four_channel.extend_from_slice(&[0; 128]);
+	four_channel.extend_from_slice(b"M.K.");
+	four_channel.extend_from_slice(&[0; ROWS * 16]);
+	four_channel.extend_from_slice(&buf[samples_start..]);
+
+	let mut module = ptmf::read_mod(&mut &four_channel[..], skip_filesize_check)
+		.map_err(|e| anyhow!("{:?}", e))?;
+	for (i, pos) in positions.iter().enumerate() {
+		module.positions.data[i] = *pos;
+	}
+	module.patterns = patterns;
+
+	Ok((module, warnings))
+}
+
+/// Writes a MOD, four channel modules with ptmf::write_mod and others
+/// with an xCHN or xxCH tag. FLT8 and CD81 modules are written as 8CHN.
+pub fn write_mod(writer: &mut dyn Write, module: &mut ptmf::PTModule) -> Result<()> {
+	let num_channels = num_channels(module);
+	if num_channels == 4 {
+		return ptmf::write_mod(writer, module).map_err(|e| anyhow!("{:?}", e));
+	}
+	let tag = tag_for(num_channels)?;
+
+	// Let ptmf write the header and samples with empty four channel
+	// patterns and replace the patterns afterwards
+	let empty = ptmf::Channel{period: 0, sample_number: 0, effect: 0};
+	let empty = ptmf::Pattern{rows: vec![ptmf::Row{channels: vec![empty; 4]}; ROWS]};
+	let num_patterns = module.patterns.len();
+	let patterns = std::mem::replace(&mut module.patterns, vec![empty; num_patterns]);
+	let mut buf = Vec::new();
+	let result = ptmf::write_mod(&mut buf, module);
+	module.patterns = patterns;
+	result.map_err(|e| anyhow!("{:?}", e))?;
+
+	// The patterns are spliced in front of the sample data, which must
+	// be what the sample headers say
+	let sample_bytes: usize = (0..31)
+		.map(|i| 20 + i * 30 + 22)
+		.map(|offset| u16::from_be_bytes([buf[offset], buf[offset + 1]]) as usize * 2)
+		.sum();
+	if buf.len() != TAG_OFFSET + 4 + num_patterns * ROWS * 16 + sample_bytes {
+		return Err(anyhow!("Unexpected module size {} with {} bytes of samples", buf.len(), sample_bytes));
+	}
+	let samples_start = buf.len() - sample_bytes;
+
+	let mut data = Vec::with_capacity(module.patterns.len() * ROWS * num_channels * 4);
+	for pattern in &module.patterns {
+		for row in &pattern.rows {
+			if row.channels.len() != num_channels {
+				return Err(anyhow!("All patterns must have {} channels", num_channels));
+			}
+			for channel in &row.channels {
+				write_channel(&mut data, channel);
+			}
+		}
+	}
+
+	writer.write_all(&buf[0..TAG_OFFSET])?;
+	writer.write_all(&tag)?;
+	writer.write_all(&data)?;
+	writer.write_all(&buf[samples_start..])?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A module with one sample of 4 bytes, tag, the positions and the
+	/// pattern data as stored in the file
+	fn module_data(tag: &[u8], positions: &[u8], patterns: &[u8]) -> Vec<u8> {
+		let mut buf = vec![0u8; TAG_OFFSET];
+		buf[20 + 22..20 + 30].copy_from_slice(&[0, 2, 0, 64, 0, 0, 0, 1]);
+		buf[950] = positions.len() as u8;
+		buf[952..952 + positions.len()].copy_from_slice(positions);
+		buf.extend_from_slice(tag);
+		buf.extend_from_slice(patterns);
+		buf.extend_from_slice(&[1, 2, 3, 4]);
+		buf
+	}
+
+	/// Pattern data where every cell has a unique effect, counting from first
+	fn pattern_data(num_patterns: usize, num_channels: usize, first: usize) -> Vec<u8> {
+		let mut data = Vec::new();
+		for i in first..first + num_patterns * ROWS * num_channels {
+			let effect = (i % 0x1000) as u16;
+			data.extend_from_slice(&[0, 0, (effect >> 8) as u8, effect as u8]);
+		}
+		data
+	}
+
+	fn effect(module: &ptmf::PTModule, pattern: usize, row: usize, channel: usize) -> u16 {
+		module.patterns[pattern].rows[row].channels[channel].effect
+	}
+
+	fn round_trip(buf: &[u8]) -> (ptmf::PTModule, Vec<u8>, ptmf::PTModule) {
+		let mut module = read_mod(&mut &buf[..], false).unwrap();
+		let mut written = Vec::new();
+		write_mod(&mut written, &mut module).unwrap();
+		let again = read_mod(&mut &written[..], false).unwrap();
+		(module, written, again)
+	}
+
+	#[test]
+	fn round_trip_xchn_and_xxch() {
+		for (tag, channels) in [(b"6CHN", 6), (b"8CHN", 8), (b"12CH", 12)].iter() {
+			let buf = module_data(*tag, &[0, 1, 0], &pattern_data(2, *channels, 0));
+			let (module, written, again) = round_trip(&buf);
+			assert_eq!(num_channels(&module), *channels);
+			assert_eq!(module.patterns.len(), 2);
+			assert_eq!(effect(&module, 1, 2, 3), ((ROWS + 2) * channels + 3) as u16);
+			// Everything but the restart byte
+			assert_eq!(written.len(), buf.len());
+			assert_eq!(&written[..951], &buf[..951]);
+			assert_eq!(&written[952..], &buf[952..]);
+			assert_eq!(again.sample_info[0].data, vec![1, 2, 3, 4]);
+		}
+	}
+
+	#[test]
+	fn flt8_read_write_read() {
+		// Pattern 0 is stored as two four channel patterns, the
+		// positions count four channel patterns
+		let mut patterns = pattern_data(1, 4, 0);
+		patterns.extend(pattern_data(1, 4, 0x800));
+		let buf = module_data(b"FLT8", &[0, 0], &patterns);
+		let (module, written, again) = round_trip(&buf);
+		assert_eq!(num_channels(&module), 8);
+		assert_eq!(module.patterns.len(), 1);
+		assert_eq!(module.positions.data[1], 0);
+		assert_eq!(effect(&module, 0, 5, 2), (5 * 4 + 2) as u16);
+		assert_eq!(effect(&module, 0, 5, 6), (0x800 + 5 * 4 + 2) as u16);
+
+		assert_eq!(&written[TAG_OFFSET..TAG_OFFSET + 4], b"8CHN");
+		for row in 0..ROWS {
+			for channel in 0..8 {
+				assert_eq!(effect(&again, 0, row, channel), effect(&module, 0, row, channel));
+			}
+		}
+		assert_eq!(again.sample_info[0].data, vec![1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn write_rejects_sample_data_not_matching_header() {
+		let buf = module_data(b"6CHN", &[0], &pattern_data(1, 6, 0));
+		let mut module = read_mod(&mut &buf[..], false).unwrap();
+		module.sample_info[0].data.push(0);
+		assert!(write_mod(&mut Vec::new(), &mut module).is_err());
+	}
+}
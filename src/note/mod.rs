@@ -0,0 +1,184 @@
+use std::cmp;
+use std::str::FromStr;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+/// Amiga PAL Paula clock, used to convert periods to sample rates
+pub const PAL_CLOCK: f64 = 3546895.0;
+
+/// ProTracker period tables, C-1 to B-3, indexed by the 4-bit finetune
+pub static FINETUNE_PERIODS: [[u16; 36]; 16] = [
+	// Finetune 0
+	[856,808,762,720,678,640,604,570,538,508,480,453,
+	 428,404,381,360,339,320,302,285,269,254,240,226,
+	 214,202,190,180,170,160,151,143,135,127,120,113],
+	// Finetune 1
+	[850,802,757,715,674,637,601,567,535,505,477,450,
+	 425,401,379,357,337,318,300,284,268,253,239,225,
+	 213,201,189,179,169,159,150,142,134,126,119,113],
+	// Finetune 2
+	[844,796,752,709,670,632,597,563,532,502,474,447,
+	 422,398,376,355,335,316,298,282,266,251,237,224,
+	 211,199,188,177,167,158,149,141,133,125,118,112],
+	// Finetune 3
+	[838,791,746,704,665,628,592,559,528,498,470,444,
+	 419,395,373,352,332,314,296,280,264,249,235,222,
+	 209,198,187,176,166,157,148,140,132,125,118,111],
+	// Finetune 4
+	[832,785,741,699,660,623,588,555,524,495,467,441,
+	 416,392,370,350,330,312,294,278,262,247,233,220,
+	 208,196,185,175,165,156,147,139,131,124,117,110],
+	// Finetune 5
+	[826,779,736,694,655,619,584,551,520,491,463,437,
+	 413,390,368,347,328,309,292,276,260,245,232,219,
+	 206,195,184,174,164,155,146,138,130,123,116,109],
+	// Finetune 6
+	[820,774,730,689,651,614,580,547,516,487,460,434,
+	 410,387,365,345,325,307,290,274,258,244,230,217,
+	 205,193,183,172,163,154,145,137,129,122,115,109],
+	// Finetune 7
+	[814,768,725,684,646,610,575,543,513,484,457,431,
+	 407,384,363,342,323,305,288,272,256,242,228,216,
+	 204,192,181,171,161,152,144,136,128,121,114,108],
+	// Finetune -8
+	[907,856,808,762,720,678,640,604,570,538,508,480,
+	 453,428,404,381,360,339,320,302,285,269,254,240,
+	 226,214,202,190,180,170,160,151,143,135,127,120],
+	// Finetune -7
+	[900,850,802,757,715,675,636,601,567,535,505,477,
+	 450,425,401,379,357,337,318,300,284,268,253,238,
+	 225,212,200,189,179,169,159,150,142,134,126,119],
+	// Finetune -6
+	[894,844,796,752,709,670,632,597,563,532,502,474,
+	 447,422,398,376,355,335,316,298,282,266,251,237,
+	 223,211,199,188,177,167,158,149,141,133,125,118],
+	// Finetune -5
+	[887,838,791,746,704,665,628,592,559,528,498,470,
+	 444,419,395,373,352,332,314,296,280,264,249,235,
+	 222,209,198,187,176,166,157,148,140,132,125,118],
+	// Finetune -4
+	[881,832,785,741,699,660,623,588,555,524,494,467,
+	 441,416,392,370,350,330,312,294,278,262,247,233,
+	 220,208,196,185,175,165,156,147,139,131,123,117],
+	// Finetune -3
+	[875,826,779,736,694,655,619,584,551,520,491,463,
+	 437,413,390,368,347,328,309,292,276,260,245,232,
+	 219,206,195,184,174,164,155,146,138,130,123,116],
+	// Finetune -2
+	[868,820,774,730,689,651,614,580,547,516,487,460,
+	 434,410,387,365,345,325,307,290,274,258,244,230,
+	 217,205,193,183,172,163,154,145,137,129,122,115],
+	// Finetune -1
+	[862,814,768,725,684,646,610,575,543,513,484,457,
+	 431,407,384,363,342,323,305,288,272,256,242,228,
+	 216,203,192,181,171,161,152,144,136,128,121,114],
+];
+
+/// Converts a period to a sample rate in Hz
+pub fn period_to_rate(period: u16) -> u32 {
+	if period == 0 {
+		return 0;
+	}
+	(PAL_CLOCK / period as f64).round() as u32
+}
+
+/// Converts the 4-bit finetune stored in the module to -8..7
+pub fn finetune_to_signed(finetune: u8) -> i8 {
+	let finetune = (finetune & 0x0f) as i8;
+	if finetune > 7 {
+		finetune - 16
+	} else {
+		finetune
+	}
+}
+
+/// Converts -8..7 to the 4-bit finetune stored in the module
+pub fn finetune_from_signed(finetune: i8) -> u8 {
+	(finetune as u8) & 0x0f
+}
+
+/// Finds the position in PERIODS with the smallest difference to period.
+/// Returns the index and the difference.
+pub fn find_nearest_note(period: u16) -> Option<(usize, i32)> {
+	let mut found = None;
+	let mut min_diff = 65536;
+	for i in 0..ptmf::PERIODS.len() {
+		let diff = (period as i32 - ptmf::PERIODS[i] as i32).abs();
+		if diff < min_diff {
+			min_diff = diff;
+			found = Some(i);
+		}
+	}
+
+	found.map(|i| (i, min_diff))
+}
+
+/// Formats the note at index in PERIODS, like C-2 or C#-2
+pub fn note_name(index: usize, use_spn: bool) -> String {
+	let mut octave = index / 12;
+	if use_spn {
+		octave += 2;
+	}
+	format!("{}-{}", ptmf::NOTE_NAMES[index % 12], octave)
+}
+
+/// Formats a period the way trackers do, three characters like C-2 or C#2,
+/// or --- for no note. Periods that are not in PERIODS get the nearest note.
+pub fn tracker_name(period: u16, use_spn: bool) -> String {
+	if period == 0 {
+		return "---".to_string();
+	}
+	match find_nearest_note(period) {
+		Some((index, _)) => {
+			let mut octave = index / 12;
+			if use_spn {
+				octave += 2;
+			}
+			let name = ptmf::NOTE_NAMES[index % 12].trim_end_matches('-');
+			if name.len() == 1 {
+				format!("{}-{}", name, octave)
+			} else {
+				format!("{}{}", name, octave)
+			}
+		},
+		None => "???".to_string(),
+	}
+}
+
+/// Parses a note like C-2, C#2 or C#-2 and returns the index in PERIODS
+pub fn parse_note(name: &str, use_spn: bool) -> Option<usize> {
+	let name = name.trim().to_uppercase();
+	if !name.is_ascii() {
+		return None;
+	}
+	let (note, octave) = if name.len() > 1 && name.as_bytes()[1] == b'#' {
+		name.split_at(2)
+	} else {
+		name.split_at(cmp::min(1, name.len()))
+	};
+	let octave = octave.trim_start_matches('-');
+
+	let note = ptmf::NOTE_NAMES.iter()
+		.position(|n| n.trim_end_matches('-').eq_ignore_ascii_case(note))?;
+	let mut octave = usize::from_str(octave).ok()?;
+	if use_spn {
+		octave = octave.checked_sub(2)?;
+	}
+
+	let index = octave * 12 + note;
+	if index < ptmf::PERIODS.len() {
+		Some(index)
+	} else {
+		None
+	}
+}
+
+/// Parses either a note name or a period number and returns the period
+pub fn parse_period(value: &str, use_spn: bool) -> Option<u16> {
+	match u16::from_str(value.trim()) {
+		Ok(period) if period > 0 => Some(period),
+		Ok(_) => None,
+		Err(_) => parse_note(value, use_spn).map(|i| ptmf::PERIODS[i]),
+	}
+}
@@ -0,0 +1,185 @@
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::sequencer::Sequencer;
+
+/// What is played when following the position and row flow of a song,
+/// including Bxx position jumps, Dxx pattern breaks, E6x pattern loops
+/// and F00, instead of just the positions in the order list
+#[derive(Debug)]
+pub struct Reachability {
+	/// For each position up to the song length, true if it is played
+	pub positions: Vec<bool>,
+	/// For each pattern and row, true if the row is played
+	pub rows: Vec<Vec<bool>>,
+	/// True if the song neither stopped nor looped within the row limit,
+	/// the result is then incomplete
+	pub aborted: bool,
+}
+
+impl Reachability {
+	pub fn unreachable_positions(&self) -> Vec<usize> {
+		(0..self.positions.len()).filter(|p| !self.positions[*p]).collect()
+	}
+
+	/// Patterns with no played rows, including patterns not in the order list
+	pub fn unreachable_patterns(&self) -> Vec<usize> {
+		(0..self.rows.len()).filter(|p| !self.rows[*p].contains(&true)).collect()
+	}
+
+	/// Ranges of rows, start and inclusive end, that are never
+	/// played in a pattern that is played
+	pub fn dead_rows(&self, pattern: usize) -> Vec<(usize, usize)> {
+		let rows = &self.rows[pattern];
+		let mut result = Vec::new();
+		if !rows.contains(&true) {
+			return result;
+		}
+		let mut start = None;
+		for (row_no, played) in rows.iter().enumerate() {
+			match (played, start) {
+				(false, None) => start = Some(row_no),
+				(true, Some(first)) => {
+					result.push((first, row_no - 1));
+					start = None;
+				},
+				_ => (),
+			}
+		}
+		if let Some(first) = start {
+			result.push((first, rows.len() - 1));
+		}
+
+		result
+	}
+}
+
+/// Plays the song until it stops or loops and records what is played.
+/// The flow does not depend on timing, so speed and tempo are ignored.
+pub fn analyze(module: &ptmf::PTModule) -> Reachability {
+	let mut positions = vec![false; module.length as usize];
+	let mut rows: Vec<Vec<bool>> = module.patterns.iter()
+		.map(|p| vec![false; p.rows.len()])
+		.collect();
+
+	let mut sequencer = Sequencer::new(module, true);
+	while let Some(tick) = sequencer.next_tick() {
+		if tick.position < positions.len() {
+			positions[tick.position] = true;
+		}
+		if let Some(played) = rows.get_mut(tick.pattern).and_then(|r| r.get_mut(tick.row)) {
+			*played = true;
+		}
+	}
+
+	Reachability{positions, rows, aborted: sequencer.aborted()}
+}
+
+/// Removes positions that are never played, renumbering Bxx position
+/// jumps, and clears all rows that are never played. Patterns are
+/// not removed, but unreachable patterns are no longer in the order list.
+/// Returns the number of removed positions and cleared rows in played patterns.
+/// Nothing is removed if the analysis was aborted, since what looks
+/// unreachable may be played later.
+pub fn remove_unreachable(module: &mut ptmf::PTModule, reachability: &Reachability) -> (usize, usize) {
+	if reachability.aborted {
+		return (0, 0);
+	}
+	let length = module.length as usize;
+
+	// New position of every reachable position
+	let mut new_positions = vec![None; length];
+	let mut new_length = 0;
+	for (pos, new_position) in new_positions.iter_mut().enumerate() {
+		if reachability.positions[pos] {
+			*new_position = Some(new_length);
+			module.positions.data[new_length] = module.positions.data[pos];
+			new_length += 1;
+		}
+	}
+	for pos in new_length..module.positions.data.len() {
+		module.positions.data[pos] = 0;
+	}
+	module.length = new_length as u8;
+
+	let mut cleared = 0;
+	for (pattern_no, pattern) in module.patterns.iter_mut().enumerate() {
+		for (row_no, row) in pattern.rows.iter_mut().enumerate() {
+			let played = reachability.rows.get(pattern_no)
+				.and_then(|r| r.get(row_no))
+				.cloned()
+				.unwrap_or(false);
+			if !played {
+				for channel in &mut row.channels {
+					channel.period = 0;
+					channel.sample_number = 0;
+					channel.effect = 0;
+				}
+				if reachability.rows.get(pattern_no).map(|r| r.contains(&true)).unwrap_or(false) {
+					cleared += 1;
+				}
+				continue;
+			}
+
+			for channel in &mut row.channels {
+				if channel.effect & 0x0f00 != 0x0b00 {
+					continue;
+				}
+				// Same wrapping as the replayer
+				let mut target = (channel.effect & 0x7f) as usize;
+				if target >= length {
+					target = 0;
+				}
+				let new_target = new_positions[target].unwrap_or(0);
+				channel.effect = 0x0b00 | new_target as u16;
+			}
+		}
+	}
+
+	(length - new_length, cleared)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{self, set_effect, set_note};
+
+	#[test]
+	fn removes_skipped_position_and_dead_rows() {
+		let mut module = testutil::module(3);
+		// Position 0 jumps to 2, which jumps back to 0
+		set_effect(&mut module, 0, 0, 0, 0x0b02);
+		set_effect(&mut module, 2, 10, 1, 0x0b00);
+		set_note(&mut module, 2, 20, 2, 428, 1);
+		set_note(&mut module, 1, 0, 0, 428, 1);
+
+		let reachability = analyze(&module);
+		assert!(!reachability.aborted);
+		assert_eq!(reachability.unreachable_positions(), vec![1]);
+		assert_eq!(reachability.unreachable_patterns(), vec![1]);
+		assert_eq!(reachability.dead_rows(2), vec![(11, 63)]);
+		let (positions, rows) = remove_unreachable(&mut module, &reachability);
+		assert_eq!((positions, rows), (1, 63 + 53));
+		assert_eq!(module.length, 2);
+		assert_eq!(&module.positions.data[0..3], &[0, 2, 0]);
+		// Jumps are renumbered
+		assert_eq!(module.patterns[0].rows[0].channels[0].effect, 0x0b01);
+		assert_eq!(module.patterns[2].rows[10].channels[1].effect, 0x0b00);
+		assert_eq!(module.patterns[2].rows[20].channels[2].period, 0);
+		assert_eq!(module.patterns[1].rows[0].channels[0].period, 0);
+	}
+
+	#[test]
+	fn nothing_is_removed_when_aborted() {
+		let mut module = testutil::module(2);
+		set_note(&mut module, 1, 5, 0, 428, 1);
+		let mut reachability = analyze(&module);
+		reachability.aborted = true;
+		reachability.positions[1] = false;
+		reachability.rows[1][5] = false;
+
+		assert_eq!(remove_unreachable(&mut module, &reachability), (0, 0));
+		assert_eq!(module.length, 2);
+		assert_eq!(module.patterns[1].rows[5].channels[0].period, 428);
+	}
+}
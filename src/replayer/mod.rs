@@ -0,0 +1,805 @@
+use anyhow::Result;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::note::{self, FINETUNE_PERIODS};
+use crate::sequencer::{Sequencer, Tick};
+
+static VIBRATO_TABLE: [u16; 32] = [
+	0, 24, 49, 74, 97, 120, 141, 161, 180, 197, 212, 224, 235, 244, 250, 253,
+	255, 253, 250, 244, 235, 224, 212, 197, 180, 161, 141, 120, 97, 74, 49, 24,
+];
+
+static FUNK_TABLE: [u8; 16] = [0, 5, 6, 7, 8, 10, 11, 13, 16, 19, 22, 26, 32, 43, 64, 128];
+
+/// Reads the period tables the way ProTracker does, as one table with
+/// 37 words per finetune where the last word is 0. Reading past the end
+/// of a finetune continues in the next one.
+fn period_table(finetune: u8, index: usize) -> u16 {
+	let index = finetune as usize * 37 + index;
+	let (finetune, index) = (index / 37, index % 37);
+	if finetune >= FINETUNE_PERIODS.len() || index >= 36 {
+		0
+	} else {
+		FINETUNE_PERIODS[finetune][index]
+	}
+}
+
+/// One Paula audio channel
+#[derive(Debug, Default, Clone)]
+struct Voice {
+	active: bool,
+	sample: usize,
+	start: usize,
+	length: usize,
+	pos: f64,
+	loop_sample: usize,
+	loop_start: usize,
+	loop_length: usize,
+	period: u16,
+	volume: u8,
+}
+
+impl Voice {
+	/// Restarts DMA, length is in words
+	fn trigger(&mut self, sample: usize, start: usize, length: u16) {
+		self.active = true;
+		self.sample = sample;
+		self.start = start;
+		self.length = length.max(1) as usize * 2;
+		self.pos = 0.0;
+	}
+
+	/// Sets what to play when the current part ends, length is in words
+	fn set_loop(&mut self, sample: usize, start: usize, length: u16) {
+		self.loop_sample = sample;
+		self.loop_start = start;
+		self.loop_length = length.max(1) as usize * 2;
+	}
+}
+
+/// ProTracker channel state
+#[derive(Debug, Default, Clone)]
+struct Channel {
+	empty: bool,
+	note: u16,
+	cmd: u16,
+	has_sample: bool,
+	sample: usize,
+	start: usize,
+	length: u16,
+	loop_start: usize,
+	replen: u16,
+	wave_start: usize,
+	period: u16,
+	volume: u8,
+	finetune: u8,
+	tone_port_direction: bool,
+	tone_port_speed: u8,
+	wanted_period: u16,
+	vibrato_cmd: u8,
+	vibrato_pos: i8,
+	tremolo_cmd: u8,
+	tremolo_pos: i8,
+	wave_control: u8,
+	gliss_funk: u8,
+	sample_offset: u8,
+	funk_offset: u8,
+}
+
+/// Plays a module the way the ProTracker 2.3 replayer does
+/// and renders the Paula channels.
+/// The E0x filter and E8x are ignored.
+pub struct Replayer<'a> {
+	module: &'a ptmf::PTModule,
+	sequencer: Sequencer<'a>,
+	sample_rate: u32,
+	/// Copy of all sample data, EFx modifies it
+	samples: Vec<Vec<i8>>,
+	channels: Vec<Channel>,
+	voices: Vec<Voice>,
+	frame_remainder: f64,
+}
+
+impl<'a> Replayer<'a> {
+	/// If cia is true Fxx >= 32 sets the tempo, otherwise Fxx always sets speed
+	pub fn new(module: &'a ptmf::PTModule, sample_rate: u32, cia: bool) -> Replayer<'a> {
+		let sequencer = Sequencer::new(module, cia);
+		let num_channels = sequencer.num_channels();
+		let samples = module.sample_info.iter()
+			.map(|si| si.data.iter().map(|b| *b as i8).collect())
+			.collect();
+		let channels = vec![Channel{empty: true, ..Default::default()}; num_channels];
+
+		Replayer{
+			module,
+			sequencer,
+			sample_rate,
+			samples,
+			channels,
+			voices: vec![Voice::default(); num_channels],
+			frame_remainder: 0.0,
+		}
+	}
+
+	pub fn num_channels(&self) -> usize {
+		self.channels.len()
+	}
+
+	pub fn sequencer(&self) -> &Sequencer<'a> {
+		&self.sequencer
+	}
+
+	/// Plays one tick and appends the output of every channel for each frame
+	/// to out, num_channels values per frame. A value is the 8-bit sample
+	/// multiplied by the volume. Returns None when the song has ended.
+	pub fn render_tick(&mut self, out: &mut Vec<i32>) -> Option<Tick> {
+		self.render(out, None)
+	}
+
+	/// Like render_tick, but also appends the sample number playing on every
+	/// channel for each frame to sample_numbers, 0 if none
+	pub fn render_tick_samples(&mut self, out: &mut Vec<i32>, sample_numbers: &mut Vec<u8>) -> Option<Tick> {
+		self.render(out, Some(sample_numbers))
+	}
+
+	fn render(&mut self, out: &mut Vec<i32>, mut sample_numbers: Option<&mut Vec<u8>>) -> Option<Tick> {
+		let tick = self.sequencer.next_tick()?;
+
+		if tick.new_row {
+			self.play_row(&tick);
+		} else {
+			for channel_no in 0..self.channels.len() {
+				self.check_effects(channel_no, tick.counter);
+			}
+		}
+
+		let frames = tick.duration * self.sample_rate as f64 + self.frame_remainder;
+		self.frame_remainder = frames.fract();
+		for _ in 0..frames as usize {
+			for channel_no in 0..self.voices.len() {
+				if let Some(ref mut sample_numbers) = sample_numbers {
+					let voice = &self.voices[channel_no];
+					let number = if voice.active { voice.sample + 1 } else { 0 };
+					sample_numbers.push(number as u8);
+				}
+				let value = self.render_voice(channel_no);
+				out.push(value);
+			}
+		}
+
+		Some(tick)
+	}
+
+	fn render_voice(&mut self, channel_no: usize) -> i32 {
+		let voice = &mut self.voices[channel_no];
+		if !voice.active || voice.period == 0 {
+			return 0;
+		}
+
+		let data = &self.samples[voice.sample];
+		let value = data.get(voice.start + voice.pos as usize).copied().unwrap_or(0) as i32;
+
+		voice.pos += note::PAL_CLOCK / voice.period as f64 / self.sample_rate as f64;
+		while voice.pos >= voice.length as f64 {
+			voice.pos -= voice.length as f64;
+			voice.sample = voice.loop_sample;
+			voice.start = voice.loop_start;
+			voice.length = voice.loop_length;
+		}
+
+		value * voice.volume.min(64) as i32
+	}
+
+	fn play_row(&mut self, tick: &Tick) {
+		let module = self.module;
+		let pattern = self.sequencer.pattern_at(tick.position);
+		for channel_no in 0..self.channels.len() {
+			let cell = pattern
+				.and_then(|p| module.patterns[p].rows.get(tick.row))
+				.and_then(|r| r.channels.get(channel_no));
+			match cell {
+				Some(cell) => self.play_voice(channel_no, cell),
+				None => {
+					let cell = ptmf::Channel{period: 0, sample_number: 0, effect: 0};
+					self.play_voice(channel_no, &cell);
+				},
+			}
+		}
+
+		// Loop pointers are always set, swapping sample on a playing channel
+		for channel_no in 0..self.channels.len() {
+			let c = &self.channels[channel_no];
+			let (sample, loop_start, replen) = (c.sample, c.loop_start, c.replen);
+			self.voices[channel_no].set_loop(sample, loop_start, replen);
+		}
+	}
+
+	fn play_voice(&mut self, channel_no: usize, cell: &ptmf::Channel) {
+		if self.channels[channel_no].empty {
+			self.voices[channel_no].period = self.channels[channel_no].period;
+		}
+
+		let c = &mut self.channels[channel_no];
+		c.empty = cell.period == 0 && cell.sample_number == 0 && cell.effect == 0;
+		c.note = cell.period & 0x0fff;
+		c.cmd = cell.effect & 0x0fff;
+
+		let number = cell.sample_number as usize;
+		if number >= 1 && number <= self.module.sample_info.len() {
+			let si = &self.module.sample_info[number - 1];
+			c.has_sample = true;
+			c.sample = number - 1;
+			c.start = 0;
+			c.finetune = si.finetune & 0x0f;
+			c.volume = si.volume.min(64);
+			c.length = si.length;
+			c.replen = si.repeat_length;
+			if si.repeat_start > 0 {
+				c.loop_start = si.repeat_start as usize * 2;
+				c.wave_start = c.loop_start;
+				c.length = si.repeat_start.saturating_add(si.repeat_length);
+			} else {
+				c.loop_start = 0;
+				c.wave_start = 0;
+			}
+			self.voices[channel_no].volume = c.volume;
+		}
+
+		if c.note == 0 {
+			self.check_more_effects(channel_no);
+			return;
+		}
+
+		if c.cmd & 0x0ff0 == 0x0e50 {
+			c.finetune = (c.cmd & 0x0f) as u8;
+			self.set_period(channel_no);
+			return;
+		}
+
+		match c.cmd >> 8 {
+			0x3 | 0x5 => {
+				self.set_tone_porta(channel_no);
+				self.check_more_effects(channel_no);
+			},
+			0x9 => {
+				self.check_more_effects(channel_no);
+				self.set_period(channel_no);
+			},
+			_ => self.set_period(channel_no),
+		}
+	}
+
+	fn set_period(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		let index = (0..37).find(|i| c.note >= period_table(0, *i)).unwrap_or(36);
+		c.period = period_table(c.finetune, index);
+
+		// Note delay, triggered later
+		if c.cmd & 0x0ff0 == 0x0ed0 {
+			self.check_more_effects(channel_no);
+			return;
+		}
+
+		if c.wave_control & 0x04 == 0 {
+			c.vibrato_pos = 0;
+		}
+		if c.wave_control & 0x40 == 0 {
+			c.tremolo_pos = 0;
+		}
+
+		let voice = &mut self.voices[channel_no];
+		voice.trigger(c.sample, c.start, c.length);
+		voice.period = c.period;
+
+		self.check_more_effects(channel_no);
+	}
+
+	fn set_tone_porta(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		let mut index = (0..37).find(|i| c.note >= period_table(c.finetune, *i)).unwrap_or(35);
+		if c.finetune & 0x08 != 0 && index != 0 {
+			index -= 1;
+		}
+
+		c.wanted_period = period_table(c.finetune, index);
+		c.tone_port_direction = false;
+		if c.period == c.wanted_period {
+			c.wanted_period = 0;
+		} else if c.wanted_period < c.period {
+			c.tone_port_direction = true;
+		}
+	}
+
+	/// Effects on the first tick of a row
+	fn check_more_effects(&mut self, channel_no: usize) {
+		self.update_funk(channel_no);
+
+		let c = &mut self.channels[channel_no];
+		match c.cmd >> 8 {
+			0x9 => {
+				if c.cmd & 0xff != 0 {
+					c.sample_offset = (c.cmd & 0xff) as u8;
+				}
+				let offset = (c.sample_offset as u16) << 7;
+				if offset < c.length {
+					c.length -= offset;
+					c.start += offset as usize * 2;
+				} else {
+					c.length = 1;
+				}
+			},
+			0xc => {
+				c.volume = ((c.cmd & 0xff) as u8).min(64);
+				self.voices[channel_no].volume = c.volume;
+			},
+			0xe => self.e_commands(channel_no, 0),
+			// Flow is handled by the sequencer
+			0xb | 0xd | 0xf => (),
+			_ => self.voices[channel_no].period = c.period,
+		}
+	}
+
+	/// Effects on the other ticks of a row, and on rows repeated by pattern delay
+	fn check_effects(&mut self, channel_no: usize, counter: u32) {
+		self.update_funk(channel_no);
+
+		let cmd = self.channels[channel_no].cmd;
+		if cmd & 0x0fff == 0 {
+			self.voices[channel_no].period = self.channels[channel_no].period;
+			return;
+		}
+
+		match cmd >> 8 {
+			0x0 => self.arpeggio(channel_no, counter),
+			0x1 => self.porta_up(channel_no, (cmd & 0xff) as u8),
+			0x2 => self.porta_down(channel_no, (cmd & 0xff) as u8),
+			0x3 => self.tone_portamento(channel_no),
+			0x4 => self.vibrato(channel_no),
+			0x5 => {
+				self.tone_port_no_change(channel_no);
+				self.volume_slide(channel_no);
+			},
+			0x6 => {
+				self.vibrato2(channel_no);
+				self.volume_slide(channel_no);
+			},
+			0xe => self.e_commands(channel_no, counter),
+			effect => {
+				self.voices[channel_no].period = self.channels[channel_no].period;
+				if effect == 0x7 {
+					self.tremolo(channel_no);
+				} else if effect == 0xa {
+					self.volume_slide(channel_no);
+				}
+			},
+		}
+	}
+
+	fn e_commands(&mut self, channel_no: usize, counter: u32) {
+		let c = &mut self.channels[channel_no];
+		let x = (c.cmd & 0x0f) as u8;
+		match (c.cmd >> 4) & 0x0f {
+			0x1 if counter == 0 => self.porta_up(channel_no, x),
+			0x2 if counter == 0 => self.porta_down(channel_no, x),
+			0x3 => c.gliss_funk = (c.gliss_funk & 0xf0) | x,
+			0x4 => c.wave_control = (c.wave_control & 0xf0) | x,
+			0x5 => c.finetune = x,
+			0x7 => c.wave_control = (x << 4) | (c.wave_control & 0x0f),
+			0x9 => {
+				if x == 0 || (counter == 0 && c.note != 0) {
+					return;
+				}
+				if counter.is_multiple_of(x as u32) {
+					self.retrig(channel_no);
+				}
+			},
+			0xa if counter == 0 => {
+				c.volume = (c.volume + x).min(64);
+				self.voices[channel_no].volume = c.volume;
+			},
+			0xb if counter == 0 => {
+				c.volume = c.volume.saturating_sub(x);
+				self.voices[channel_no].volume = c.volume;
+			},
+			0xc if counter == x as u32 => {
+				c.volume = 0;
+				self.voices[channel_no].volume = 0;
+			},
+			0xd if counter == x as u32 && c.note != 0 => self.retrig(channel_no),
+			0xf if counter == 0 => {
+				c.gliss_funk = (x << 4) | (c.gliss_funk & 0x0f);
+				if x != 0 {
+					self.update_funk(channel_no);
+				}
+			},
+			// E0x filter, E6x and EEx are handled by the sequencer, E8x does
+			// nothing, the rest only on some ticks
+			_ => (),
+		}
+	}
+
+	fn retrig(&mut self, channel_no: usize) {
+		let c = &self.channels[channel_no];
+		let voice = &mut self.voices[channel_no];
+		voice.trigger(c.sample, c.start, c.length);
+		voice.period = c.period;
+		voice.set_loop(c.sample, c.loop_start, c.replen);
+	}
+
+	fn update_funk(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		let speed = c.gliss_funk >> 4;
+		if speed == 0 {
+			return;
+		}
+
+		c.funk_offset = c.funk_offset.saturating_add(FUNK_TABLE[speed as usize]);
+		if c.funk_offset < 128 {
+			return;
+		}
+		c.funk_offset = 0;
+
+		if c.has_sample {
+			c.wave_start += 1;
+			if c.wave_start >= c.loop_start + c.replen as usize * 2 {
+				c.wave_start = c.loop_start;
+			}
+			if let Some(b) = self.samples[c.sample].get_mut(c.wave_start) {
+				*b = -1 - *b;
+			}
+		}
+	}
+
+	fn arpeggio(&mut self, channel_no: usize, counter: u32) {
+		let c = &self.channels[channel_no];
+		let arp = match counter % 3 {
+			0 => {
+				self.voices[channel_no].period = c.period;
+				return;
+			},
+			1 => (c.cmd >> 4) & 0x0f,
+			_ => c.cmd & 0x0f,
+		} as usize;
+
+		if let Some(index) = (0..37).find(|i| c.period >= period_table(c.finetune, *i)) {
+			self.voices[channel_no].period = period_table(c.finetune, index + arp);
+		}
+	}
+
+	fn porta_up(&mut self, channel_no: usize, amount: u8) {
+		let c = &mut self.channels[channel_no];
+		c.period = (c.period as i32 - amount as i32).max(113) as u16;
+		self.voices[channel_no].period = c.period;
+	}
+
+	fn porta_down(&mut self, channel_no: usize, amount: u8) {
+		let c = &mut self.channels[channel_no];
+		c.period = (c.period as i32 + amount as i32).min(856) as u16;
+		self.voices[channel_no].period = c.period;
+	}
+
+	fn tone_portamento(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		if c.cmd & 0xff != 0 {
+			c.tone_port_speed = (c.cmd & 0xff) as u8;
+			c.cmd &= 0x0f00;
+		}
+		self.tone_port_no_change(channel_no);
+	}
+
+	fn tone_port_no_change(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		if c.wanted_period == 0 {
+			return;
+		}
+
+		let speed = c.tone_port_speed as i32;
+		let mut period = c.period as i32;
+		let wanted = c.wanted_period as i32;
+		if c.tone_port_direction {
+			period -= speed;
+			if period <= wanted {
+				period = wanted;
+				c.wanted_period = 0;
+			}
+		} else {
+			period += speed;
+			if period >= wanted {
+				period = wanted;
+				c.wanted_period = 0;
+			}
+		}
+		c.period = period as u16;
+
+		if c.gliss_funk & 0x0f != 0 {
+			// Glissando, slide in semitones
+			if let Some(index) = (0..37).find(|i| c.period >= period_table(c.finetune, *i)) {
+				self.voices[channel_no].period = period_table(c.finetune, index);
+			}
+		} else {
+			self.voices[channel_no].period = c.period;
+		}
+	}
+
+	/// Returns the waveform value at pos for waveform 0-3.
+	/// ramp_pos decides the direction of the ramp.
+	fn waveform(waveform: u8, pos: i8, ramp_pos: i8) -> u16 {
+		let index = ((pos as u8) >> 2) & 0x1f;
+		match waveform & 0x03 {
+			0 => VIBRATO_TABLE[index as usize],
+			1 => {
+				let data = (index as u16) << 3;
+				if ramp_pos < 0 { 255 - data } else { data }
+			},
+			_ => 255,
+		}
+	}
+
+	fn vibrato(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		let param = (c.cmd & 0xff) as u8;
+		if param & 0x0f != 0 {
+			c.vibrato_cmd = (c.vibrato_cmd & 0xf0) | (param & 0x0f);
+		}
+		if param & 0xf0 != 0 {
+			c.vibrato_cmd = (c.vibrato_cmd & 0x0f) | (param & 0xf0);
+		}
+		self.vibrato2(channel_no);
+	}
+
+	fn vibrato2(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		let data = Replayer::waveform(c.wave_control, c.vibrato_pos, c.vibrato_pos);
+		let data = ((data * (c.vibrato_cmd & 0x0f) as u16) >> 7) as i32;
+		let period = if c.vibrato_pos >= 0 {
+			c.period as i32 + data
+		} else {
+			c.period as i32 - data
+		};
+		self.voices[channel_no].period = period.max(0) as u16;
+		c.vibrato_pos = c.vibrato_pos.wrapping_add(((c.vibrato_cmd >> 2) & 0x3c) as i8);
+	}
+
+	fn tremolo(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		let param = (c.cmd & 0xff) as u8;
+		if param & 0x0f != 0 {
+			c.tremolo_cmd = (c.tremolo_cmd & 0xf0) | (param & 0x0f);
+		}
+		if param & 0xf0 != 0 {
+			c.tremolo_cmd = (c.tremolo_cmd & 0x0f) | (param & 0xf0);
+		}
+
+		// ProTracker uses the vibrato position for the ramp direction
+		let data = Replayer::waveform(c.wave_control >> 4, c.tremolo_pos, c.vibrato_pos);
+		let data = ((data * (c.tremolo_cmd & 0x0f) as u16) >> 6) as i32;
+		let volume = if c.tremolo_pos >= 0 {
+			(c.volume as i32 + data).min(64)
+		} else {
+			(c.volume as i32 - data).max(0)
+		};
+		self.voices[channel_no].volume = volume as u8;
+		c.tremolo_pos = c.tremolo_pos.wrapping_add(((c.tremolo_cmd >> 2) & 0x3c) as i8);
+	}
+
+	fn volume_slide(&mut self, channel_no: usize) {
+		let c = &mut self.channels[channel_no];
+		let param = (c.cmd & 0xff) as u8;
+		if param >> 4 == 0 {
+			c.volume = c.volume.saturating_sub(param & 0x0f);
+		} else {
+			c.volume = (c.volume + (param >> 4)).min(64);
+		}
+		self.voices[channel_no].volume = c.volume;
+	}
+}
+
+/// One tick rendered by render_song
+pub struct RenderedTick<'a> {
+	pub num_channels: usize,
+	/// num_channels values per frame, see render_tick
+	pub values: &'a [i32],
+	/// num_channels sample numbers per frame, see render_tick_samples
+	pub sample_numbers: &'a [u8],
+}
+
+/// Plays a module until it ends or loops, or for at most max_seconds,
+/// and passes every tick to output, so the song is never in memory.
+/// Returns the number of seconds played.
+pub fn render_song(module: &ptmf::PTModule, sample_rate: u32, cia: bool, max_seconds: Option<f64>,
+	output: &mut dyn FnMut(&RenderedTick) -> Result<()>) -> Result<f64> {
+	let mut player = Replayer::new(module, sample_rate, cia);
+	let num_channels = player.num_channels();
+	let mut values = Vec::new();
+	let mut sample_numbers = Vec::new();
+	let mut seconds = 0.0;
+	while let Some(tick) = player.render_tick_samples(&mut values, &mut sample_numbers) {
+		output(&RenderedTick{num_channels, values: &values, sample_numbers: &sample_numbers})?;
+		values.clear();
+		sample_numbers.clear();
+		seconds += tick.duration;
+		if let Some(max_seconds) = max_seconds {
+			if seconds >= max_seconds {
+				break;
+			}
+		}
+	}
+
+	Ok(seconds)
+}
+
+/// Scale so two channels per side use the full 16-bit range
+fn output_scale(num_channels: usize) -> i32 {
+	if num_channels <= 4 { 2 } else { 1 }
+}
+
+fn to_i16(value: i32) -> i16 {
+	value.clamp(-32768, 32767) as i16
+}
+
+/// Mixes to interleaved 16-bit stereo, with only the values for which
+/// include returns true. include gets the index in values.
+fn mix(values: &[i32], num_channels: usize, include: &dyn Fn(usize) -> bool) -> Vec<i16> {
+	let num_channels = num_channels.max(1);
+	let scale = output_scale(num_channels);
+	let mut result = Vec::with_capacity(values.len() / num_channels * 2);
+	for (frame_no, frame) in values.chunks_exact(num_channels).enumerate() {
+		let mut left = 0;
+		let mut right = 0;
+		for (channel_no, value) in frame.iter().enumerate() {
+			if !include(frame_no * num_channels + channel_no) {
+				continue;
+			}
+			match channel_no % 4 {
+				0 | 3 => left += value,
+				_ => right += value,
+			}
+		}
+		result.push(to_i16(left * scale));
+		result.push(to_i16(right * scale));
+	}
+
+	result
+}
+
+/// Mixes the output of render_tick to interleaved 16-bit stereo with
+/// Amiga hard panning, channel 1 and 4 left, channel 2 and 3 right
+pub fn mix_stereo(values: &[i32], num_channels: usize) -> Vec<i16> {
+	mix(values, num_channels, &|_| true)
+}
+
+/// Like mix_stereo, but only what is played with sample_number
+pub fn mix_sample(values: &[i32], sample_numbers: &[u8], num_channels: usize, sample_number: u8) -> Vec<i16> {
+	mix(values, num_channels, &|i| sample_numbers[i] == sample_number)
+}
+
+/// One channel as 16-bit mono, with the same scale as mix_stereo
+/// so the channels of one side add up to the mix
+pub fn channel_mono(values: &[i32], num_channels: usize, channel_no: usize) -> Vec<i16> {
+	let scale = output_scale(num_channels);
+	values.iter()
+		.skip(channel_no)
+		.step_by(num_channels.max(1))
+		.map(|value| to_i16(value * scale))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{self, set_effect, set_note};
+
+	const RATE: u32 = 10000;
+
+	/// The values of a channel for each tick until the song ends
+	fn render_channel(module: &ptmf::PTModule, cia: bool, channel_no: usize) -> Vec<Vec<i32>> {
+		let mut player = Replayer::new(module, RATE, cia);
+		let mut ticks = Vec::new();
+		loop {
+			let mut out = Vec::new();
+			if player.render_tick(&mut out).is_none() {
+				break;
+			}
+			ticks.push(out.iter().skip(channel_no).step_by(4).cloned().collect());
+		}
+		ticks
+	}
+
+	fn module_with_note(effect: u16) -> ptmf::PTModule {
+		let mut module = testutil::module(1);
+		testutil::set_square(&mut module, 1, 16);
+		set_note(&mut module, 0, 0, 0, 428, 1);
+		set_effect(&mut module, 0, 0, 0, effect);
+		module
+	}
+
+	#[test]
+	fn frames_per_tick() {
+		let module = testutil::module(1);
+		let mut player = Replayer::new(&module, RATE, true);
+		let mut out = Vec::new();
+		let tick = player.render_tick(&mut out).unwrap();
+		assert_eq!(out.len(), 200 * 4);
+		assert_eq!(tick.counter, 0);
+		assert!(tick.new_row);
+
+		// Tempo 50 is 2.5 times longer ticks than 125
+		let mut module = testutil::module(1);
+		set_effect(&mut module, 0, 0, 0, 0x0f32);
+		let mut player = Replayer::new(&module, RATE, true);
+		let mut out = Vec::new();
+		player.render_tick(&mut out).unwrap();
+		assert_eq!(out.len(), 500 * 4);
+	}
+
+	#[test]
+	fn note_plays_sample_at_its_volume() {
+		let ticks = render_channel(&module_with_note(0), true, 0);
+		assert_eq!(ticks.len(), 64 * 6);
+		assert_eq!(ticks[0][0], 64 * 64);
+		assert!(ticks[0].contains(&(-64 * 64)));
+		// The loop keeps playing
+		assert_eq!(ticks[100].iter().map(|v| v.abs()).max(), Some(64 * 64));
+		// Other channels are silent
+		assert!(render_channel(&module_with_note(0), true, 1).iter().flatten().all(|v| *v == 0));
+	}
+
+	#[test]
+	fn period_sets_playback_rate() {
+		// C-2 plays PAL_CLOCK / 428 bytes per second, the square wave
+		// has one period per 32 bytes
+		let ticks = render_channel(&module_with_note(0), true, 0);
+		let values: Vec<i32> = ticks.iter().take(50).flatten().cloned().collect();
+		let rising = values.windows(2).filter(|w| w[0] < 0 && w[1] > 0).count() as f64;
+		let expected = note::PAL_CLOCK / 428.0 / 32.0 * values.len() as f64 / RATE as f64;
+		assert!((rising - expected).abs() <= 1.0, "{} {}", rising, expected);
+	}
+
+	#[test]
+	fn set_volume() {
+		let ticks = render_channel(&module_with_note(0x0c20), true, 0);
+		assert_eq!(ticks[0][0], 64 * 32);
+	}
+
+	#[test]
+	fn volume_slide_on_ticks_after_the_first() {
+		let ticks = render_channel(&module_with_note(0x0a02), true, 0);
+		let volume = |tick: usize| ticks[tick].iter().map(|v| v.abs()).max().unwrap() / 64;
+		assert_eq!(volume(0), 64);
+		assert_eq!(volume(1), 62);
+		assert_eq!(volume(5), 54);
+		// The slide stops with the row
+		assert_eq!(volume(6), 54);
+	}
+
+	#[test]
+	fn note_delay() {
+		let ticks = render_channel(&module_with_note(0x0ed3), true, 0);
+		assert!(ticks[..3].iter().flatten().all(|v| *v == 0));
+		assert_eq!(ticks[3][0], 64 * 64);
+	}
+
+	#[test]
+	fn note_cut() {
+		let ticks = render_channel(&module_with_note(0x0ec2), true, 0);
+		assert!(ticks[1].iter().any(|v| *v != 0));
+		assert!(ticks[2].iter().all(|v| *v == 0));
+	}
+
+	#[test]
+	fn render_song_stops_at_f00() {
+		let mut module = module_with_note(0);
+		set_effect(&mut module, 0, 1, 1, 0x0f00);
+		// The tick with F00 is the last one
+		let mut ticks = Vec::new();
+		let seconds = render_song(&module, RATE, true, None, &mut |tick| {
+			assert_eq!(tick.values.len(), tick.sample_numbers.len());
+			ticks.push(tick.values.len() / tick.num_channels);
+			Ok(())
+		}).unwrap();
+		assert!((seconds - 7.0 * 0.02).abs() < 1e-9);
+		assert_eq!(ticks, vec![200; 7]);
+	}
+}
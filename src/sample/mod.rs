@@ -0,0 +1,178 @@
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::iff;
+use crate::note;
+use crate::wav;
+
+/// Max sample length in bytes that fits in a ProTracker sample header
+pub const MAX_SAMPLE_LENGTH: usize = 0xffff * 2;
+
+/// A sample loaded from a WAV, 8SVX or RAW file
+#[derive(Debug, Default)]
+pub struct Sample {
+	pub name: Option<String>,
+	pub sample_rate: Option<u32>,
+	/// Mono sample data, -1.0 to 1.0
+	pub data: Vec<f32>,
+	/// Loop start and end in samples, end is exclusive
+	pub sample_loop: Option<(usize, usize)>,
+	pub volume: Option<u8>,
+	pub finetune: Option<i8>,
+}
+
+/// Loads a sample, WAV and 8SVX are detected from the header,
+/// anything else is treated as RAW 8-bit signed
+pub fn load(buf: &[u8]) -> Result<Sample> {
+	if buf.len() >= 12 && &buf[0..4] == b"RIFF" && &buf[8..12] == b"WAVE" {
+		wav::read_sample(buf)
+	} else if buf.len() >= 12 && &buf[0..4] == b"FORM" && &buf[8..12] == b"8SVX" {
+		iff::read_sample(buf)
+	} else {
+		Ok(from_raw(buf))
+	}
+}
+
+/// Creates a sample from RAW 8-bit signed data
+pub fn from_raw(buf: &[u8]) -> Sample {
+	Sample{
+		data: buf.iter().map(|b| *b as i8 as f32 / 128.0).collect(),
+		..Default::default()
+	}
+}
+
+impl Sample {
+	/// Resamples to sample_rate using windowed sinc interpolation.
+	/// When the rate is lowered the data is low-pass filtered below the
+	/// new Nyquist frequency to avoid aliasing. Loop points are scaled accordingly.
+	pub fn resample(&mut self, sample_rate: u32) -> Result<()> {
+		let source_rate = match self.sample_rate {
+			Some(0) => return Err(anyhow!("Sample rate is 0 Hz, unable to resample")),
+			Some(rate) => rate,
+			None => return Err(anyhow!("Sample rate is unknown, unable to resample")),
+		};
+		if source_rate == sample_rate || self.data.is_empty() {
+			self.sample_rate = Some(sample_rate);
+			return Ok(());
+		}
+		if sample_rate == 0 {
+			return Err(anyhow!("Unable to resample to 0 Hz"));
+		}
+
+		let ratio = source_rate as f64 / sample_rate as f64;
+		let new_len = (self.data.len() as f64 / ratio).round() as usize;
+		let data = (0..new_len)
+			.map(|i| sinc_interpolate(&self.data, i as f64 * ratio, ratio))
+			.collect();
+
+		self.sample_loop = self.sample_loop.map(|(start, end)| {
+			let start = (start as f64 / ratio).round() as usize;
+			let end = (end as f64 / ratio).round() as usize;
+			(start.min(new_len), end.min(new_len))
+		});
+		self.data = data;
+		self.sample_rate = Some(sample_rate);
+
+		Ok(())
+	}
+
+	/// Converts to 8-bit signed and replaces the data, length,
+	/// loop and, if known, name, volume and finetune of a sample.
+	/// Length is made even and must fit in a ProTracker sample.
+	pub fn apply(&self, si: &mut ptmf::SampleInfo) -> Result<()> {
+		let mut data: Vec<u8> = self.data.iter()
+			.map(|s| (s * 128.0).round().clamp(-128.0, 127.0) as i8 as u8)
+			.collect();
+		if data.len() & 1 == 1 {
+			data.push(0);
+		}
+		if data.len() > MAX_SAMPLE_LENGTH {
+			return Err(anyhow!("Sample is {} bytes, max is {} bytes", data.len(), MAX_SAMPLE_LENGTH));
+		}
+
+		si.length = (data.len() / 2) as u16;
+		si.data = data;
+
+		// Loop points must be on even bytes
+		si.repeat_start = 0;
+		si.repeat_length = 1;
+		if let Some((start, end)) = self.sample_loop {
+			let start = start / 2;
+			let end = end.div_ceil(2);
+			if end > start + 1 && end <= si.length as usize {
+				si.repeat_start = start as u16;
+				si.repeat_length = (end - start) as u16;
+			}
+		}
+
+		if let Some(ref name) = self.name {
+			si.name = name.chars().take(22).collect();
+		}
+		if let Some(volume) = self.volume {
+			si.volume = volume.min(64);
+		} else if si.volume == 0 {
+			si.volume = 64;
+		}
+		si.finetune = note::finetune_from_signed(self.finetune.unwrap_or(0));
+
+		Ok(())
+	}
+}
+
+/// Zero crossings of the sinc on each side of a sample
+const SINC_ZERO_CROSSINGS: f64 = 16.0;
+
+fn sinc(x: f64) -> f64 {
+	if x.abs() < 1e-9 {
+		1.0
+	} else {
+		let x = x * std::f64::consts::PI;
+		x.sin() / x
+	}
+}
+
+/// Blackman window, x from -1.0 to 1.0
+fn blackman(x: f64) -> f64 {
+	if x.abs() >= 1.0 {
+		return 0.0;
+	}
+	let x = (x + 1.0) * std::f64::consts::PI;
+	0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos()
+}
+
+/// Interpolates data at pos with a Blackman windowed sinc. With ratio,
+/// source rate divided by destination rate, above 1.0 the cutoff is
+/// lowered to the destination Nyquist frequency. Data outside the
+/// sample is treated as silence.
+fn sinc_interpolate(data: &[f32], pos: f64, ratio: f64) -> f32 {
+	let cutoff = if ratio > 1.0 { 1.0 / ratio } else { 1.0 };
+	let half_width = SINC_ZERO_CROSSINGS / cutoff;
+
+	let first = (pos - half_width).ceil().max(0.0) as usize;
+	let last = ((pos + half_width).floor() as usize).min(data.len() - 1);
+	let mut sum = 0.0;
+	for (j, value) in data.iter().enumerate().take(last + 1).skip(first) {
+		let x = pos - j as f64;
+		sum += *value as f64 * cutoff * sinc(x * cutoff) * blackman(x / half_width);
+	}
+
+	sum as f32
+}
+
+/// Parses "Finetune: x Volume: y" comments written by save
+pub fn parse_comment(comment: &str, sample: &mut Sample) {
+	let words: Vec<&str> = comment.split_whitespace().collect();
+	for pair in words.windows(2) {
+		match pair[0] {
+			"Finetune:" => if let Ok(finetune) = pair[1].parse::<i8>() {
+				sample.finetune = Some(finetune.clamp(-8, 7));
+			},
+			"Volume:" => if let Ok(volume) = pair[1].parse::<u8>() {
+				sample.volume = Some(volume.min(64));
+			},
+			_ => (),
+		}
+	}
+}
@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+/// Ticks per second in vblank mode, PAL
+pub const VBLANK_RATE: f64 = 50.0;
+pub const DEFAULT_SPEED: u32 = 6;
+pub const DEFAULT_TEMPO: u32 = 125;
+
+/// Stop if a song plays more rows than this without looping,
+/// can happen with nested E6x loops
+const MAX_ROWS: usize = 128 * 64 * 16;
+
+/// What happens during one tick
+#[derive(Debug, Clone)]
+pub struct Tick {
+	pub position: usize,
+	pub pattern: usize,
+	pub row: usize,
+	/// The tick counter, 0 on the first tick of a row
+	pub counter: u32,
+	/// True if new notes are read, false on the other ticks
+	/// and when a row is repeated by EEx pattern delay
+	pub new_row: bool,
+	pub speed: u32,
+	pub tempo: u32,
+	/// Length of this tick in seconds
+	pub duration: f64,
+}
+
+/// Follows the position and row flow of a module tick by tick the way
+/// the ProTracker replayer does, including Fxx speed and tempo,
+/// Bxx position jump, Dxx pattern break, E6x pattern loop and
+/// EEx pattern delay. Notes and other effects are ignored.
+pub struct Sequencer<'a> {
+	module: &'a ptmf::PTModule,
+	cia: bool,
+	num_channels: usize,
+	position: usize,
+	row: usize,
+	current_position: usize,
+	current_row: usize,
+	counter: u32,
+	speed: u32,
+	tempo: u32,
+	pattern_delay: u32,
+	pattern_delay2: u32,
+	break_pos: usize,
+	break_flag: bool,
+	jump_flag: bool,
+	loop_row: Vec<usize>,
+	loop_count: Vec<u32>,
+	/// Position, row and E6x loop rows of every row played, and the
+	/// number of rows played before it was first played
+	visited: HashMap<(usize, usize, Vec<usize>), usize>,
+	rows_played: usize,
+	stopped: bool,
+	aborted: bool,
+	loop_target: Option<(usize, usize)>,
+	loop_start: usize,
+}
+
+impl<'a> Sequencer<'a> {
+	/// If cia is true Fxx >= 32 sets the tempo, otherwise Fxx always sets speed
+	pub fn new(module: &'a ptmf::PTModule, cia: bool) -> Sequencer<'a> {
+		let num_channels = module.patterns.first()
+			.and_then(|p| p.rows.first())
+			.map(|r| r.channels.len())
+			.unwrap_or(4);
+
+		Sequencer{
+			module,
+			cia,
+			num_channels,
+			position: 0,
+			row: 0,
+			current_position: 0,
+			current_row: 0,
+			// Play the first row on the first tick
+			counter: DEFAULT_SPEED - 1,
+			speed: DEFAULT_SPEED,
+			tempo: DEFAULT_TEMPO,
+			pattern_delay: 0,
+			pattern_delay2: 0,
+			break_pos: 0,
+			break_flag: false,
+			jump_flag: false,
+			loop_row: vec![0; num_channels],
+			loop_count: vec![0; num_channels],
+			visited: HashMap::new(),
+			rows_played: 0,
+			stopped: module.length == 0,
+			aborted: false,
+			loop_target: None,
+			loop_start: 0,
+		}
+	}
+
+	pub fn num_channels(&self) -> usize {
+		self.num_channels
+	}
+
+	/// True if the song was stopped by F00
+	pub fn stopped(&self) -> bool {
+		self.stopped
+	}
+
+	/// True if the song played too many rows without stopping or looping
+	pub fn aborted(&self) -> bool {
+		self.aborted
+	}
+
+	/// The position and row the song loops back to, when it has looped
+	pub fn loop_target(&self) -> Option<(usize, usize)> {
+		self.loop_target
+	}
+
+	/// The number of rows played before the loop target was
+	/// first played, when the song has looped
+	pub fn loop_start(&self) -> Option<usize> {
+		self.loop_target.map(|_| self.loop_start)
+	}
+
+	/// True if the song has stopped, looped or was aborted
+	pub fn ended(&self) -> bool {
+		self.stopped || self.aborted || self.loop_target.is_some()
+	}
+
+	/// Pattern number at position, if there is such a pattern
+	pub fn pattern_at(&self, position: usize) -> Option<usize> {
+		let pattern = self.module.positions.data[position] as usize;
+		if pattern < self.module.patterns.len() {
+			Some(pattern)
+		} else {
+			None
+		}
+	}
+
+	fn rows_at(&self, position: usize) -> usize {
+		self.pattern_at(position)
+			.map(|p| self.module.patterns[p].rows.len())
+			.unwrap_or(64)
+	}
+
+	/// Seconds per tick with the current speed and tempo
+	pub fn tick_duration(&self) -> f64 {
+		if self.cia {
+			2.5 / self.tempo as f64
+		} else {
+			1.0 / VBLANK_RATE
+		}
+	}
+
+	/// Advances one tick. Returns None when the song has stopped or
+	/// is about to play a row that has already been played, i.e. loops.
+	pub fn next_tick(&mut self) -> Option<Tick> {
+		if self.ended() {
+			return None;
+		}
+
+		self.counter += 1;
+		if self.counter < self.speed {
+			return Some(self.tick(false));
+		}
+
+		self.counter = 0;
+		self.current_position = self.position;
+		self.current_row = self.row;
+		let new_row = self.pattern_delay2 == 0;
+
+		if new_row {
+			// A row played again, outside of a pattern loop and with the same
+			// E6x loop rows, means the song loops. With other loop rows a later
+			// E6x can take the song somewhere it has not been.
+			let key = (self.position, self.row, self.loop_row.clone());
+			if let Some(start) = self.visited.get(&key) {
+				if self.loop_count.iter().all(|c| *c == 0) {
+					self.loop_target = Some((self.position, self.row));
+					self.loop_start = *start;
+					return None;
+				}
+			}
+			self.visited.entry(key).or_insert(self.rows_played);
+			self.rows_played += 1;
+			if self.rows_played > MAX_ROWS {
+				self.aborted = true;
+				return None;
+			}
+		}
+
+		self.process_row(new_row);
+
+		self.row += 1;
+		if self.pattern_delay > 0 {
+			self.pattern_delay2 = self.pattern_delay;
+			self.pattern_delay = 0;
+		}
+		if self.pattern_delay2 > 0 {
+			self.pattern_delay2 -= 1;
+			if self.pattern_delay2 > 0 {
+				self.row -= 1;
+			}
+		}
+		if self.break_flag {
+			self.break_flag = false;
+			self.row = self.break_pos;
+			self.break_pos = 0;
+		}
+		if self.row >= self.rows_at(self.current_position) {
+			self.next_position();
+		}
+		if self.jump_flag {
+			self.next_position();
+		}
+
+		// After F00 the row is played but nothing after it
+		Some(self.tick(new_row))
+	}
+
+	fn tick(&self, new_row: bool) -> Tick {
+		Tick{
+			position: self.current_position,
+			pattern: self.module.positions.data[self.current_position] as usize,
+			row: self.current_row,
+			counter: self.counter,
+			new_row,
+			speed: self.speed,
+			tempo: self.tempo,
+			duration: self.tick_duration(),
+		}
+	}
+
+	fn next_position(&mut self) {
+		self.row = self.break_pos;
+		self.break_pos = 0;
+		self.jump_flag = false;
+		self.position = (self.position + 1) & 0x7f;
+		if self.position >= self.module.length as usize {
+			self.position = 0;
+		}
+	}
+
+	/// Handles the flow commands of the current row
+	fn process_row(&mut self, new_row: bool) {
+		let pattern = match self.pattern_at(self.current_position) {
+			Some(pattern) => &self.module.patterns[pattern],
+			None => return,
+		};
+		let row = match pattern.rows.get(self.current_row) {
+			Some(row) => row,
+			None => return,
+		};
+
+		for (channel_no, channel) in row.channels.iter().enumerate().take(self.num_channels) {
+			let cmd = (channel.effect >> 8) & 0x0f;
+			let param = (channel.effect & 0xff) as u32;
+
+			if cmd == 0xe {
+				let x = param & 0x0f;
+				match param >> 4 {
+					// Pattern loop, also on rows repeated by pattern delay
+					0x6 => {
+						if x == 0 {
+							self.loop_row[channel_no] = self.current_row;
+						} else {
+							if self.loop_count[channel_no] == 0 {
+								self.loop_count[channel_no] = x;
+							} else {
+								self.loop_count[channel_no] -= 1;
+							}
+							if self.loop_count[channel_no] != 0 {
+								self.break_pos = self.loop_row[channel_no];
+								self.break_flag = true;
+							}
+						}
+					},
+					0xe if self.pattern_delay2 == 0 => self.pattern_delay = x + 1,
+					_ => (),
+				}
+				continue;
+			}
+
+			if !new_row {
+				continue;
+			}
+
+			match cmd {
+				0xb => {
+					self.position = (param as u8).wrapping_sub(1) as usize;
+					self.break_pos = 0;
+					self.jump_flag = true;
+				},
+				0xd => {
+					// Parameter is decimal
+					let pos = (param >> 4) * 10 + (param & 0x0f);
+					self.break_pos = if pos > 63 { 0 } else { pos as usize };
+					self.jump_flag = true;
+				},
+				0xf => {
+					if param == 0 {
+						self.stopped = true;
+					} else if self.cia && param >= 32 {
+						self.tempo = param;
+					} else {
+						self.speed = param;
+						self.counter = 0;
+					}
+				},
+				_ => (),
+			}
+		}
+	}
+}
+
+/// How long a song plays, see song_duration
+#[derive(Debug, Clone)]
+pub struct Duration {
+	pub seconds: f64,
+	pub ticks: usize,
+	/// Rows played, rows repeated by EEx pattern delay are not counted
+	pub rows: usize,
+	/// The position and row the song loops back to, and when
+	/// that row was first played in seconds
+	pub loop_target: Option<(usize, usize, f64)>,
+	/// True if the song was stopped by F00
+	pub stopped: bool,
+	/// True if the song neither stopped nor looped within the row limit
+	pub aborted: bool,
+}
+
+impl Duration {
+	/// Length in PAL vblank frames
+	pub fn vblank_frames(&self) -> u64 {
+		(self.seconds * VBLANK_RATE).round() as u64
+	}
+}
+
+/// Plays a song until it stops or loops and returns how long it played
+pub fn song_duration(module: &ptmf::PTModule, cia: bool) -> Duration {
+	let mut sequencer = Sequencer::new(module, cia);
+	let mut seconds = 0.0;
+	let mut ticks = 0;
+	let mut rows = 0;
+	// When each row was played
+	let mut row_starts = Vec::new();
+	while let Some(tick) = sequencer.next_tick() {
+		if tick.new_row {
+			rows += 1;
+			row_starts.push(seconds);
+		}
+		seconds += tick.duration;
+		ticks += 1;
+	}
+
+	let loop_target = sequencer.loop_target().map(|(position, row)| {
+		let start = sequencer.loop_start()
+			.and_then(|r| row_starts.get(r))
+			.cloned()
+			.unwrap_or(0.0);
+		(position, row, start)
+	});
+
+	Duration{
+		seconds,
+		ticks,
+		rows,
+		loop_target,
+		stopped: sequencer.stopped(),
+		aborted: sequencer.aborted(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{self, set_effect};
+
+	fn rows_played(module: &ptmf::PTModule) -> Vec<(usize, usize)> {
+		let mut sequencer = Sequencer::new(module, true);
+		let mut rows = Vec::new();
+		while let Some(tick) = sequencer.next_tick() {
+			if tick.new_row {
+				rows.push((tick.position, tick.row));
+			}
+		}
+		rows
+	}
+
+	#[test]
+	fn plays_positions_in_order_and_loops() {
+		let module = testutil::module(2);
+		let duration = song_duration(&module, true);
+		assert_eq!(duration.rows, 128);
+		assert_eq!(duration.ticks, 128 * 6);
+		assert_eq!(duration.loop_target, Some((0, 0, 0.0)));
+		assert!(!duration.stopped);
+		assert!(!duration.aborted);
+	}
+
+	#[test]
+	fn position_jump_and_pattern_break_on_same_row() {
+		let mut module = testutil::module(3);
+		set_effect(&mut module, 0, 10, 0, 0x0b02);
+		set_effect(&mut module, 0, 10, 1, 0x0d05);
+		let rows = rows_played(&module);
+		assert_eq!(rows[10], (0, 10));
+		assert_eq!(rows[11], (2, 5));
+	}
+
+	#[test]
+	fn position_jump_clears_earlier_pattern_break() {
+		let mut module = testutil::module(3);
+		set_effect(&mut module, 0, 10, 0, 0x0d05);
+		set_effect(&mut module, 0, 10, 1, 0x0b02);
+		let rows = rows_played(&module);
+		assert_eq!(rows[11], (2, 0));
+	}
+
+	#[test]
+	fn pattern_break_parameter_is_decimal() {
+		let mut module = testutil::module(2);
+		set_effect(&mut module, 0, 0, 0, 0x0d10);
+		let rows = rows_played(&module);
+		assert_eq!(rows[1], (1, 10));
+		// Rows 10-63 of position 1, then back to the first row
+		assert_eq!(rows.len(), 1 + 54);
+		assert_eq!(song_duration(&module, true).loop_target.map(|l| (l.0, l.1)), Some((0, 0)));
+	}
+
+	#[test]
+	fn pattern_loop_repeats_rows() {
+		let mut module = testutil::module(1);
+		set_effect(&mut module, 0, 0, 0, 0x0e60);
+		set_effect(&mut module, 0, 3, 0, 0x0e62);
+		let rows = rows_played(&module);
+		assert_eq!(rows.len(), 64 + 2 * 4);
+		let expected: Vec<usize> = (0..4).chain(0..4).chain(0..4).chain(4..64).collect();
+		assert_eq!(rows.iter().map(|r| r.1).collect::<Vec<usize>>(), expected);
+	}
+
+	#[test]
+	fn pattern_delay_repeats_a_row_without_new_notes() {
+		let mut module = testutil::module(1);
+		set_effect(&mut module, 0, 0, 0, 0x0ee2);
+		let duration = song_duration(&module, true);
+		assert_eq!(duration.rows, 64);
+		assert_eq!(duration.ticks, 64 * 6 + 2 * 6);
+	}
+
+	#[test]
+	fn f00_stops_the_song() {
+		let mut module = testutil::module(2);
+		set_effect(&mut module, 0, 5, 2, 0x0f00);
+		let duration = song_duration(&module, true);
+		assert!(duration.stopped);
+		assert_eq!(duration.rows, 6);
+		assert_eq!(duration.loop_target, None);
+	}
+
+	#[test]
+	fn tick_length_cia_and_vblank() {
+		let mut module = testutil::module(1);
+		assert!((song_duration(&module, true).seconds - 64.0 * 6.0 * 0.02).abs() < 1e-9);
+		assert!((song_duration(&module, false).seconds - 64.0 * 6.0 * 0.02).abs() < 1e-9);
+
+		// F20 is tempo 32 with CIA timing and speed 32 with vblank timing
+		set_effect(&mut module, 0, 0, 0, 0x0f20);
+		let cia = song_duration(&module, true);
+		assert!((cia.seconds - 64.0 * 6.0 * 2.5 / 32.0).abs() < 1e-9);
+		let vblank = song_duration(&module, false);
+		assert_eq!(vblank.ticks, 64 * 32);
+		assert_eq!(vblank.vblank_frames(), 64 * 32);
+	}
+
+	#[test]
+	fn loop_target_after_position_jump() {
+		let mut module = testutil::module(3);
+		set_effect(&mut module, 2, 10, 3, 0x0b01);
+		let duration = song_duration(&module, true);
+		assert_eq!(duration.rows, 64 * 2 + 11);
+		let (position, row, start) = duration.loop_target.unwrap();
+		assert_eq!((position, row), (1, 0));
+		assert!((start - 64.0 * 6.0 * 0.02).abs() < 1e-9);
+	}
+
+	#[test]
+	fn revisited_row_with_other_loop_row_is_not_a_loop() {
+		let mut module = testutil::module(1);
+		set_effect(&mut module, 0, 1, 0, 0x0e61);
+		set_effect(&mut module, 0, 2, 0, 0x0e60);
+		set_effect(&mut module, 0, 3, 0, 0x0b00);
+		let rows: Vec<usize> = rows_played(&module).iter().map(|(_, row)| *row).collect();
+		// The second time, E61 loops back to row 2
+		assert_eq!(rows, vec![0, 1, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1]);
+		let duration = song_duration(&module, true);
+		let (position, row, start) = duration.loop_target.unwrap();
+		assert_eq!((position, row), (0, 2));
+		// Row 2 with loop row 2 was first played as the 9th row
+		assert!((start - 8.0 * 6.0 * 0.02).abs() < 1e-9);
+	}
+}
@@ -0,0 +1,261 @@
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+const NUM_SAMPLES: usize = 15;
+const SAMPLE_HEADER_SIZE: usize = 30;
+const LENGTH_OFFSET: usize = 20 + NUM_SAMPLES * SAMPLE_HEADER_SIZE;
+const PATTERNS_OFFSET: usize = LENGTH_OFFSET + 2 + 128;
+const PATTERN_SIZE: usize = 64 * 4 * 4;
+
+/// The tempo byte value that means default vblank timing
+const DEFAULT_TEMPO_BYTE: u8 = 0x78;
+/// CIA timer clock on a PAL Amiga
+const CIA_CLOCK: f64 = 709379.0;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+	u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Guesses if buf is a 15-sample Soundtracker module, there is no tag
+/// so the header is checked for sane values and the file size
+pub fn is_soundtracker(buf: &[u8]) -> bool {
+	if buf.len() < PATTERNS_OFFSET {
+		return false;
+	}
+
+	let mut sample_bytes = 0;
+	for i in 0..NUM_SAMPLES {
+		let header = &buf[20 + i * SAMPLE_HEADER_SIZE..20 + (i + 1) * SAMPLE_HEADER_SIZE];
+		let name_ok = header[0..22].iter().all(|c| *c == 0 || (*c >= 0x20 && *c < 0x7f) || *c >= 0xa0);
+		// No finetune in Soundtracker
+		if !name_ok || header[24] != 0 || header[25] > 64 {
+			return false;
+		}
+		sample_bytes += read_u16(header, 22) as usize * 2;
+	}
+
+	let length = buf[LENGTH_OFFSET] as usize;
+	let positions = &buf[LENGTH_OFFSET + 2..PATTERNS_OFFSET];
+	if length == 0 || length > 128 || positions.iter().any(|p| *p > 63) {
+		return false;
+	}
+	let num_patterns = *positions.iter().max().unwrap_or(&0) as usize + 1;
+
+	buf.len() >= PATTERNS_OFFSET + num_patterns * PATTERN_SIZE + sample_bytes
+}
+
+/// Tempo in BPM from the Soundtracker tempo byte, where the song uses
+/// CIA timing. None means the default vblank timing. In NoiseTracker
+/// and later this byte is the restart position.
+pub fn tempo(tempo_byte: u8) -> Option<u32> {
+	if tempo_byte == 0 || tempo_byte == DEFAULT_TEMPO_BYTE || tempo_byte >= 240 {
+		return None;
+	}
+	// The CIA timer is set to (240 - tempo) * 122 and one tick is 2.5/bpm seconds
+	let ticks_per_second = CIA_CLOCK / ((240 - tempo_byte as u32) * 122) as f64;
+	Some((ticks_per_second * 2.5).round().clamp(32.0, 255.0) as u32)
+}
+
+/// A 15-sample module converted to a 31-sample M.K. module
+#[derive(Debug)]
+pub struct Converted {
+	pub data: Vec<u8>,
+	/// Tempo byte for apply_tempo
+	pub tempo_byte: u8,
+	/// Things that may not play as in the original
+	pub warnings: Vec<String>,
+}
+
+/// Converts Ultimate Soundtracker effects to ProTracker. 1xy arpeggio
+/// becomes 0xy and 2xy pitch bend becomes 20y if y is set, otherwise 10x.
+/// cell is a pattern cell, four bytes.
+fn convert_ultimate_effect(cell: &mut [u8]) {
+	let param = cell[3];
+	let (command, param) = match cell[2] & 0x0f {
+		1 => (0, param),
+		2 if param & 0x0f != 0 => (2, param & 0x0f),
+		2 => (1, param >> 4),
+		_ => return,
+	};
+	cell[2] = (cell[2] & 0xf0) | command;
+	cell[3] = param;
+}
+
+/// Converts a 15-sample module to a 31-sample M.K. module.
+/// Ultimate Soundtracker stores the repeat start in bytes instead
+/// of words, this is detected from the sample length. If it is found
+/// and only effects 1 and 2 are used, they are converted from
+/// Ultimate Soundtracker arpeggio and pitch bend. Otherwise they are
+/// kept as portamento, with a warning if no other effects are used,
+/// since the module then may be from Ultimate Soundtracker.
+pub fn to_protracker(buf: &[u8]) -> Result<Converted> {
+	if !is_soundtracker(buf) {
+		return Err(anyhow!("Not a 15-sample Soundtracker module"));
+	}
+
+	let mut warnings = Vec::new();
+	let mut byte_repeat_start = false;
+	let mut result = buf[0..20].to_vec();
+	for i in 0..NUM_SAMPLES {
+		let mut header = buf[20 + i * SAMPLE_HEADER_SIZE..20 + (i + 1) * SAMPLE_HEADER_SIZE].to_vec();
+		let length = read_u16(&header, 22) as usize * 2;
+		let repeat_start = read_u16(&header, 26) as usize;
+		let repeat_length = read_u16(&header, 28) as usize * 2;
+		if repeat_length > 2 && repeat_start * 2 + repeat_length > length && repeat_start + repeat_length <= length {
+			header[26..28].copy_from_slice(&((repeat_start / 2) as u16).to_be_bytes());
+			byte_repeat_start = true;
+		}
+		result.extend_from_slice(&header);
+	}
+	for _ in NUM_SAMPLES..31 {
+		let mut header = vec![0; SAMPLE_HEADER_SIZE];
+		// Repeat length 1 word means no loop
+		header[29] = 1;
+		result.extend_from_slice(&header);
+	}
+
+	let tempo_byte = buf[LENGTH_OFFSET + 1];
+	result.push(buf[LENGTH_OFFSET]);
+	result.push(127);
+	result.extend_from_slice(&buf[LENGTH_OFFSET + 2..PATTERNS_OFFSET]);
+	result.extend_from_slice(b"M.K.");
+	let patterns_start = result.len();
+	result.extend_from_slice(&buf[PATTERNS_OFFSET..]);
+
+	let positions = &buf[LENGTH_OFFSET + 2..PATTERNS_OFFSET];
+	let num_patterns = *positions.iter().max().unwrap_or(&0) as usize + 1;
+	let patterns = &mut result[patterns_start..patterns_start + num_patterns * PATTERN_SIZE];
+	let used = |command: u8| patterns.chunks_exact(4).any(|c| c[2] & 0x0f == command && (command != 0 || c[3] != 0));
+	let ultimate_effects = used(1) || used(2);
+	let other_effects = (0..16).filter(|c| *c != 1 && *c != 2).any(used);
+	if ultimate_effects && !other_effects {
+		if byte_repeat_start {
+			for cell in patterns.chunks_exact_mut(4) {
+				convert_ultimate_effect(cell);
+			}
+			warnings.push("Converted Ultimate Soundtracker arpeggio and pitch bend to ProTracker effects".to_string());
+		} else {
+			warnings.push("Effects 1 and 2 are read as portamento, in Ultimate Soundtracker they are arpeggio and pitch bend".to_string());
+		}
+	}
+
+	Ok(Converted{data: result, tempo_byte, warnings})
+}
+
+/// Sets the tempo from the Soundtracker tempo byte with an Fxx command on the
+/// first row played, in the first channel without an effect, so no effect
+/// is overwritten. Returns false if there is no free channel.
+pub fn apply_tempo(module: &mut ptmf::PTModule, tempo_byte: u8) -> bool {
+	let bpm = match tempo(tempo_byte) {
+		Some(bpm) => bpm,
+		None => return true,
+	};
+	let pattern = module.positions.data[0] as usize;
+	let row = match module.patterns.get_mut(pattern).and_then(|p| p.rows.get_mut(0)) {
+		Some(row) => row,
+		None => return false,
+	};
+	match row.channels.iter_mut().find(|c| c.effect == 0) {
+		Some(channel) => {
+			channel.effect = 0x0f00 | bpm as u16;
+			true
+		},
+		None => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::multichannel;
+
+	/// A 15-sample module with one pattern and one 32 byte sample,
+	/// looped from repeat_start in the header
+	fn module_data(repeat_start: u16, tempo_byte: u8) -> Vec<u8> {
+		let mut buf = vec![0u8; PATTERNS_OFFSET + PATTERN_SIZE + 32];
+		let header = &mut buf[20..20 + SAMPLE_HEADER_SIZE];
+		header[22..24].copy_from_slice(&16u16.to_be_bytes());
+		header[25] = 64;
+		header[26..28].copy_from_slice(&repeat_start.to_be_bytes());
+		header[28..30].copy_from_slice(&8u16.to_be_bytes());
+		buf[LENGTH_OFFSET] = 1;
+		buf[LENGTH_OFFSET + 1] = tempo_byte;
+		buf
+	}
+
+	fn set_cell(buf: &mut [u8], row: usize, channel: usize, cell: [u8; 4]) {
+		let offset = PATTERNS_OFFSET + (row * 4 + channel) * 4;
+		buf[offset..offset + 4].copy_from_slice(&cell);
+	}
+
+	fn effect(module: &ptmf::PTModule, row: usize, channel: usize) -> u16 {
+		module.patterns[0].rows[row].channels[channel].effect
+	}
+
+	#[test]
+	fn ultimate_effects_are_converted() {
+		// Repeat start 16 bytes, only valid in bytes
+		let mut buf = module_data(16, DEFAULT_TEMPO_BYTE);
+		set_cell(&mut buf, 0, 0, [0x01, 0xac, 0x11, 0x37]);
+		set_cell(&mut buf, 1, 0, [0x00, 0x00, 0x02, 0x30]);
+		set_cell(&mut buf, 2, 0, [0x00, 0x00, 0x02, 0x05]);
+
+		let (module, warnings) = multichannel::read_mod_with_warnings(&mut &buf[..], false).unwrap();
+		assert_eq!(warnings.len(), 1);
+		assert_eq!(module.sample_info[0].repeat_start, 8);
+		assert_eq!(effect(&module, 0, 0), 0x0037);
+		assert_eq!(effect(&module, 1, 0), 0x0103);
+		assert_eq!(effect(&module, 2, 0), 0x0205);
+		assert_eq!(module.patterns[0].rows[0].channels[0].sample_number, 1);
+	}
+
+	#[test]
+	fn effects_1_and_2_without_ultimate_loop_are_kept_with_a_warning() {
+		let mut buf = module_data(4, DEFAULT_TEMPO_BYTE);
+		set_cell(&mut buf, 0, 0, [0x01, 0xac, 0x11, 0x37]);
+
+		let (module, warnings) = multichannel::read_mod_with_warnings(&mut &buf[..], false).unwrap();
+		assert_eq!(warnings.len(), 1);
+		assert_eq!(effect(&module, 0, 0), 0x0137);
+	}
+
+	#[test]
+	fn effects_1_and_2_with_other_effects_are_portamento() {
+		let mut buf = module_data(16, DEFAULT_TEMPO_BYTE);
+		set_cell(&mut buf, 0, 0, [0x01, 0xac, 0x11, 0x37]);
+		set_cell(&mut buf, 1, 0, [0x00, 0x00, 0x0c, 0x20]);
+
+		let (module, warnings) = multichannel::read_mod_with_warnings(&mut &buf[..], false).unwrap();
+		assert!(warnings.is_empty());
+		assert_eq!(effect(&module, 0, 0), 0x0137);
+	}
+
+	#[test]
+	fn tempo_goes_in_a_free_channel() {
+		let mut buf = module_data(0, 0x50);
+		set_cell(&mut buf, 0, 0, [0x00, 0x00, 0x0c, 0x20]);
+		set_cell(&mut buf, 0, 1, [0x00, 0x00, 0x0c, 0x10]);
+
+		let (module, warnings) = multichannel::read_mod_with_warnings(&mut &buf[..], false).unwrap();
+		assert!(warnings.is_empty());
+		assert_eq!(effect(&module, 0, 0), 0x0c20);
+		assert_eq!(effect(&module, 0, 1), 0x0c10);
+		assert_eq!(effect(&module, 0, 2), 0x0f00 | tempo(0x50).unwrap() as u16);
+	}
+
+	#[test]
+	fn tempo_without_a_free_channel_is_a_warning() {
+		let mut buf = module_data(0, 0x50);
+		for channel in 0..4 {
+			set_cell(&mut buf, 0, channel, [0x00, 0x00, 0x0c, 0x20 + channel as u8]);
+		}
+
+		let (module, warnings) = multichannel::read_mod_with_warnings(&mut &buf[..], false).unwrap();
+		assert_eq!(warnings.len(), 1);
+		for channel in 0..4 {
+			assert_eq!(effect(&module, 0, channel), 0x0c20 + channel as u16);
+		}
+	}
+}
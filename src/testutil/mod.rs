@@ -0,0 +1,39 @@
+// Modules built for the unit tests
+use modfile::ptmf;
+
+/// A module with num_patterns empty four channel patterns,
+/// played in order, and no samples
+pub fn module(num_patterns: usize) -> ptmf::PTModule {
+	let mut buf = vec![0u8; 1084 + 64 * 16];
+	buf[1080..1084].copy_from_slice(b"M.K.");
+	let mut module = ptmf::read_mod(&mut &buf[..], false).unwrap();
+	module.patterns = vec![module.patterns[0].clone(); num_patterns];
+	for position in 0..num_patterns {
+		module.positions.data[position] = position as u8;
+	}
+	module.length = num_patterns as u8;
+	module
+}
+
+pub fn set_effect(module: &mut ptmf::PTModule, pattern: usize, row: usize, channel: usize, effect: u16) {
+	module.patterns[pattern].rows[row].channels[channel].effect = effect;
+}
+
+pub fn set_note(module: &mut ptmf::PTModule, pattern: usize, row: usize, channel: usize, period: u16, sample_number: u8) {
+	let cell = &mut module.patterns[pattern].rows[row].channels[channel];
+	cell.period = period;
+	cell.sample_number = sample_number;
+}
+
+/// Sets sample number to a looped square wave with volume 64
+pub fn set_square(module: &mut ptmf::PTModule, number: u8, length: u16) {
+	let si = &mut module.sample_info[number as usize - 1];
+	si.length = length;
+	si.volume = 64;
+	si.finetune = 0;
+	si.repeat_start = 0;
+	si.repeat_length = length;
+	si.data = (0..length as usize * 2)
+		.map(|i| if i < length as usize { 64 } else { (-64i8) as u8 })
+		.collect();
+}
@@ -0,0 +1,235 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::note;
+
+/// Formats a period as a note like C-2, or as the period in decimal
+/// like 430 if it is not in ptmf::PERIODS, so no period is lost when
+/// the text is read back by parse_channel
+pub fn format_period(period: u16, use_spn: bool) -> String {
+	if period == 0 || ptmf::PERIODS.contains(&period) {
+		note::tracker_name(period, use_spn)
+	} else {
+		format!("{:03}", period)
+	}
+}
+
+/// Formats a channel like C-2 01 C40, sample number and effect in hex
+pub fn format_channel(channel: &ptmf::Channel, use_spn: bool) -> String {
+	format!("{} {:02X} {:03X}", format_period(channel.period, use_spn), channel.sample_number, channel.effect)
+}
+
+/// Formats a row like 00 | C-2 01 C40 | --- 00 000 | ...
+/// With highlight_sync, E8x commands are listed after a ; at the end of the row.
+pub fn format_row(row_no: usize, row: &ptmf::Row, use_spn: bool, highlight_sync: bool) -> String {
+	let mut result = format!("{:02}", row_no);
+	for channel in &row.channels {
+		result.push_str(" | ");
+		result.push_str(&format_channel(channel, use_spn));
+	}
+
+	if highlight_sync {
+		let sync: Vec<String> = row.channels.iter().enumerate()
+			.filter(|(_, c)| c.effect & 0x0ff0 == 0x0e80)
+			.map(|(channel_no, c)| format!("E8{:X} ch{}", c.effect & 0x0f, channel_no + 1))
+			.collect();
+		if !sync.is_empty() {
+			result.push_str("  ; sync ");
+			result.push_str(&sync.join(", "));
+		}
+	}
+
+	result
+}
+
+/// Writes a pattern as a block of text, a header line followed by one line per row
+pub fn write_pattern(writer: &mut dyn Write, header: &str, pattern: &ptmf::Pattern, use_spn: bool, highlight_sync: bool) -> io::Result<()> {
+	writeln!(writer, "{}", header)?;
+	for (row_no, row) in pattern.rows.iter().enumerate() {
+		writeln!(writer, "{}", format_row(row_no, row, use_spn, highlight_sync))?;
+	}
+	writeln!(writer)
+}
+
+/// A channel read from text, period is 0 for no note
+#[derive(Debug, Clone, Default)]
+pub struct TextChannel {
+	pub period: u16,
+	pub sample_number: u8,
+	pub effect: u16,
+}
+
+/// A pattern read from text, rows not in the text are empty
+#[derive(Debug)]
+pub struct TextPattern {
+	pub number: usize,
+	/// Row number and channels
+	pub rows: Vec<(usize, Vec<TextChannel>)>,
+}
+
+/// Parses a channel like C-2 01 C40, 430 01 C40 or --- 00 000.
+/// Notes get the period from ptmf::PERIODS, the replayer applies
+/// the finetune of the sample.
+pub fn parse_channel(text: &str, use_spn: bool) -> Result<TextChannel> {
+	let fields: Vec<&str> = text.split_whitespace().collect();
+	if fields.len() != 3 {
+		return Err(anyhow!("Expected note, sample and effect in '{}'", text.trim()));
+	}
+
+	let period = if fields[0] == "---" {
+		0
+	} else {
+		match note::parse_period(fields[0], use_spn) {
+			Some(period) if period <= 0x0fff => period,
+			_ => return Err(anyhow!("Invalid note '{}'", fields[0])),
+		}
+	};
+	let sample_number = u8::from_str_radix(fields[1], 16)
+		.map_err(|_| anyhow!("Invalid sample number '{}'", fields[1]))?;
+	if sample_number > 31 {
+		return Err(anyhow!("Invalid sample number '{}'", fields[1]));
+	}
+	let effect = u16::from_str_radix(fields[2], 16)
+		.map_err(|_| anyhow!("Invalid effect '{}'", fields[2]))?;
+	if effect > 0x0fff {
+		return Err(anyhow!("Invalid effect '{}'", fields[2]));
+	}
+
+	Ok(TextChannel{period, sample_number, effect})
+}
+
+/// Parses patterns written by write_pattern. Each block starts with a
+/// header line ending with Pattern <number>, followed by rows like
+/// 00 | C-2 01 C40 | --- 00 000. Text after ; is a comment,
+/// other lines without | are ignored.
+pub fn parse_patterns(text: &str, use_spn: bool) -> Result<Vec<TextPattern>> {
+	let mut result: Vec<TextPattern> = Vec::new();
+	for (line_no, line) in text.lines().enumerate() {
+		let line = line.split(';').next().unwrap_or("").trim();
+		if line.is_empty() {
+			continue;
+		}
+
+		let fields: Vec<&str> = line.split('|').collect();
+		if fields.len() == 1 {
+			let words: Vec<&str> = line.split_whitespace().collect();
+			let number = match words.as_slice() {
+				[.., "Pattern", number] => usize::from_str(number).ok(),
+				_ => None,
+			};
+			if let Some(number) = number {
+				result.push(TextPattern{number, rows: Vec::new()});
+			}
+			continue;
+		}
+
+		let pattern = match result.last_mut() {
+			Some(pattern) => pattern,
+			None => return Err(anyhow!("Line {}: Row before the first pattern header", line_no + 1)),
+		};
+		let row_no = usize::from_str(fields[0].trim())
+			.map_err(|_| anyhow!("Line {}: Invalid row number '{}'", line_no + 1, fields[0].trim()))?;
+		let mut channels = Vec::new();
+		for field in &fields[1..] {
+			let channel = parse_channel(field, use_spn)
+				.map_err(|e| anyhow!("Line {}: {}", line_no + 1, e))?;
+			channels.push(channel);
+		}
+		pattern.rows.push((row_no, channels));
+	}
+
+	Ok(result)
+}
+
+/// Replaces a pattern in module. Patterns can not be added, since
+/// the number of patterns in a MOD is given by the play order.
+pub fn apply_pattern(module: &mut ptmf::PTModule, pattern: &TextPattern) -> Result<()> {
+	let num_channels = module.patterns.first()
+		.and_then(|p| p.rows.first())
+		.map(|r| r.channels.len())
+		.unwrap_or(4);
+	let num_rows = match module.patterns.get(pattern.number) {
+		Some(p) => p.rows.len(),
+		None => return Err(anyhow!("Pattern {}: Only {} patterns, unable to add it", pattern.number, module.patterns.len())),
+	};
+
+	let empty = ptmf::Channel{period: 0, sample_number: 0, effect: 0};
+	let mut rows = vec![ptmf::Row{channels: vec![empty; num_channels]}; num_rows];
+
+	let mut text_rows: Vec<&(usize, Vec<TextChannel>)> = pattern.rows.iter().collect();
+	text_rows.sort_by_key(|(row_no, _)| *row_no);
+
+	for (row_no, channels) in text_rows {
+		if *row_no >= num_rows {
+			return Err(anyhow!("Pattern {}: Invalid row {}", pattern.number, row_no));
+		}
+		if channels.len() != num_channels {
+			return Err(anyhow!("Pattern {}: Row {} has {} channels, module has {}", pattern.number, row_no, channels.len(), num_channels));
+		}
+		for (channel_no, channel) in channels.iter().enumerate() {
+			rows[*row_no].channels[channel_no] = ptmf::Channel{
+				period: channel.period,
+				sample_number: channel.sample_number,
+				effect: channel.effect,
+			};
+		}
+	}
+
+	module.patterns[pattern.number].rows = rows;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil;
+
+	#[test]
+	fn periods_not_in_the_table_are_written_as_numbers() {
+		let channel = |period| ptmf::Channel{period, sample_number: 1, effect: 0x0c40};
+		assert_eq!(format_channel(&channel(428), false), "C-2 01 C40");
+		assert_eq!(format_channel(&channel(430), false), "430 01 C40");
+		assert_eq!(format_channel(&channel(50), false), "050 01 C40");
+		assert_eq!(format_channel(&channel(1720), false), "1720 01 C40");
+		assert_eq!(format_channel(&channel(0), false), "--- 01 C40");
+	}
+
+	#[test]
+	fn parse_notes_and_periods() {
+		assert_eq!(parse_channel("C-2 01 C40", false).unwrap().period, 428);
+		assert_eq!(parse_channel("C-4 01 C40", true).unwrap().period, 428);
+		assert_eq!(parse_channel("430 01 C40", false).unwrap().period, 430);
+		assert_eq!(parse_channel("--- 00 000", false).unwrap().period, 0);
+		assert!(parse_channel("5000 01 C40", false).is_err());
+		assert!(parse_channel("H-2 01 C40", false).is_err());
+	}
+
+	#[test]
+	fn pattern_text_round_trip_keeps_periods() {
+		let mut module = testutil::module(1);
+		testutil::set_note(&mut module, 0, 0, 0, 428, 1);
+		testutil::set_note(&mut module, 0, 1, 1, 430, 2);
+		testutil::set_note(&mut module, 0, 2, 2, 1720, 3);
+		testutil::set_effect(&mut module, 0, 2, 2, 0x0f06);
+		let original = module.patterns[0].clone();
+
+		let mut text = Vec::new();
+		write_pattern(&mut text, "Position 0 Pattern 0", &original, false, false).unwrap();
+		for row in &mut module.patterns[0].rows {
+			row.channels[0].period = 0;
+		}
+		let patterns = parse_patterns(&String::from_utf8(text).unwrap(), false).unwrap();
+		apply_pattern(&mut module, &patterns[0]).unwrap();
+
+		for (row, original_row) in module.patterns[0].rows.iter().zip(original.rows.iter()) {
+			for (c, o) in row.channels.iter().zip(original_row.channels.iter()) {
+				assert_eq!((c.period, c.sample_number, c.effect), (o.period, o.sample_number, o.effect));
+			}
+		}
+	}
+}
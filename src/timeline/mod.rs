@@ -0,0 +1,182 @@
+use std::io::{self, Write};
+use serde::Serialize;
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::sequencer::{self, Sequencer};
+
+/// An E8x command in playback order
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncEvent {
+	pub position: usize,
+	pub pattern: usize,
+	pub row: usize,
+	/// Channel, 1 is the first channel like in show --patterns
+	pub channel: usize,
+	/// The x in E8x
+	pub value: u8,
+	/// PAL vblank frame, rounded to the nearest frame with CIA timing
+	pub frame: u64,
+	pub milliseconds: f64,
+}
+
+/// Plays a song until it stops or loops and returns every E8x command
+/// in the order it is played. Rows played again by E6x pattern loops
+/// give new events, rows repeated by EEx pattern delay do not.
+pub fn sync_events(module: &ptmf::PTModule, cia: bool) -> Vec<SyncEvent> {
+	let mut sequencer = Sequencer::new(module, cia);
+	let mut result = Vec::new();
+	let mut seconds = 0.0;
+	while let Some(tick) = sequencer.next_tick() {
+		if tick.new_row && tick.pattern < module.patterns.len() {
+			if let Some(row) = module.patterns[tick.pattern].rows.get(tick.row) {
+				for (channel_no, channel) in row.channels.iter().enumerate() {
+					if channel.effect & 0x0ff0 != 0x0e80 {
+						continue;
+					}
+					result.push(SyncEvent{
+						position: tick.position,
+						pattern: tick.pattern,
+						row: tick.row,
+						channel: channel_no + 1,
+						value: (channel.effect & 0x0f) as u8,
+						frame: (seconds * sequencer::VBLANK_RATE).round() as u64,
+						// Rounded to microseconds to hide rounding errors
+						milliseconds: (seconds * 1_000_000.0).round() / 1000.0,
+					});
+				}
+			}
+		}
+		seconds += tick.duration;
+	}
+
+	result
+}
+
+/// Output format of the timeline
+#[derive(Debug, PartialEq)]
+pub enum Format {
+	Json,
+	Csv,
+	CHeader,
+	Asm,
+}
+
+impl Format {
+	/// Format from a file extension, .json, .csv, .h or .s, .i, .asm
+	pub fn from_filename(filename: &str) -> Option<Format> {
+		let extension = filename.rsplit('.').next()?.to_lowercase();
+		match extension.as_str() {
+			"json" => Some(Format::Json),
+			"csv" => Some(Format::Csv),
+			"h" => Some(Format::CHeader),
+			"s" | "i" | "asm" => Some(Format::Asm),
+			_ => None,
+		}
+	}
+}
+
+pub fn write(writer: &mut dyn Write, events: &[SyncEvent], format: &Format) -> io::Result<()> {
+	match format {
+		Format::Json => write_json(writer, events),
+		Format::Csv => write_csv(writer, events),
+		Format::CHeader => write_c_header(writer, events),
+		Format::Asm => write_asm(writer, events),
+	}
+}
+
+pub fn write_json(writer: &mut dyn Write, events: &[SyncEvent]) -> io::Result<()> {
+	serde_json::to_writer_pretty(&mut *writer, events)?;
+	writeln!(writer)
+}
+
+pub fn write_csv(writer: &mut dyn Write, events: &[SyncEvent]) -> io::Result<()> {
+	writeln!(writer, "position,pattern,row,channel,value,frame,milliseconds")?;
+	for e in events {
+		writeln!(writer, "{},{},{},{},{},{},{:.3}",
+			e.position, e.pattern, e.row, e.channel, e.value, e.frame, e.milliseconds)?;
+	}
+
+	Ok(())
+}
+
+/// Writes a C header with an array of events,
+/// terminated by an event with frame 0xffffffff
+pub fn write_c_header(writer: &mut dyn Write, events: &[SyncEvent]) -> io::Result<()> {
+	writeln!(writer, "/* E8x sync events, generated by modtool */")?;
+	writeln!(writer, "#ifndef MODTOOL_SYNC_EVENTS_H")?;
+	writeln!(writer, "#define MODTOOL_SYNC_EVENTS_H")?;
+	writeln!(writer)?;
+	writeln!(writer, "#define NUM_SYNC_EVENTS {}", events.len())?;
+	writeln!(writer)?;
+	writeln!(writer, "struct sync_event {{")?;
+	writeln!(writer, "\tunsigned char position, pattern, row, channel, value;")?;
+	writeln!(writer, "\tunsigned int frame, milliseconds;")?;
+	writeln!(writer, "}};")?;
+	writeln!(writer)?;
+	writeln!(writer, "static const struct sync_event sync_events[NUM_SYNC_EVENTS + 1] = {{")?;
+	for e in events {
+		writeln!(writer, "\t{{{}, {}, {}, {}, {}, {}, {}}},",
+			e.position, e.pattern, e.row, e.channel, e.value, e.frame, e.milliseconds.round() as u64)?;
+	}
+	writeln!(writer, "\t{{0, 0, 0, 0, 0, 0xffffffff, 0xffffffff}}")?;
+	writeln!(writer, "}};")?;
+	writeln!(writer)?;
+	writeln!(writer, "#endif")
+}
+
+/// Writes a 68k assembler include with a dc.l frame and a dc.w line with
+/// value, position, pattern, row and channel per event, terminated by
+/// frame -1. Frames above $7fffffff are an error, they would be
+/// negative or end the list.
+pub fn write_asm(writer: &mut dyn Write, events: &[SyncEvent]) -> io::Result<()> {
+	if let Some(e) = events.iter().find(|e| e.frame > 0x7fff_ffff) {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame {} does not fit in dc.l", e.frame)));
+	}
+
+	writeln!(writer, "; E8x sync events, generated by modtool")?;
+	writeln!(writer, "; dc.l frame, dc.w value, position, pattern, row, channel")?;
+	writeln!(writer, "sync_num_events:")?;
+	writeln!(writer, "\tdc.w\t{}", events.len())?;
+	writeln!(writer, "sync_events:")?;
+	for e in events {
+		writeln!(writer, "\tdc.l\t{}\t; {:.0} ms", e.frame, e.milliseconds)?;
+		writeln!(writer, "\tdc.w\t{},{},{},{},{}",
+			e.value, e.position, e.pattern, e.row, e.channel)?;
+	}
+	writeln!(writer, "\tdc.l\t-1")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{self, set_effect};
+
+	#[test]
+	fn events_in_play_order_with_channels_from_1() {
+		let mut module = testutil::module(2);
+		set_effect(&mut module, 1, 0, 3, 0x0e82);
+		set_effect(&mut module, 0, 1, 0, 0x0e81);
+		let events = sync_events(&module, true);
+		assert_eq!(events.len(), 2);
+		assert_eq!((events[0].pattern, events[0].row, events[0].channel, events[0].value), (0, 1, 1, 1));
+		assert_eq!(events[0].frame, 6);
+		assert_eq!((events[1].pattern, events[1].row, events[1].channel, events[1].value), (1, 0, 4, 2));
+		assert_eq!(events[1].frame, 64 * 6);
+	}
+
+	#[test]
+	fn asm_frames_are_longs() {
+		let event = SyncEvent{position: 0, pattern: 0, row: 0, channel: 1, value: 1,
+			frame: 0x12345, milliseconds: 0.0};
+		let mut buf = Vec::new();
+		write_asm(&mut buf, std::slice::from_ref(&event)).unwrap();
+		let text = String::from_utf8(buf).unwrap();
+		assert!(text.contains("\tdc.l\t74565\t"));
+		assert!(text.ends_with("\tdc.l\t-1\n"));
+
+		let event = SyncEvent{frame: 0x8000_0000, ..event};
+		assert!(write_asm(&mut Vec::new(), &[event]).is_err());
+	}
+}
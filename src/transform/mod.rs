@@ -0,0 +1,863 @@
+use std::cmp;
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::analysis;
+use crate::multichannel;
+use crate::note::{self, FINETUNE_PERIODS};
+use crate::sample;
+
+/// Selects which pattern cells an operation applies to.
+/// Empty lists select everything.
+#[derive(Debug, Default)]
+pub struct Selection {
+	/// Pattern numbers, starting at 0
+	pub patterns: Vec<usize>,
+	/// Channels, starting at 0
+	pub channels: Vec<usize>,
+	/// Sample numbers 1-31
+	pub samples: Vec<u8>,
+}
+
+impl Selection {
+	fn pattern(&self, pattern_no: usize) -> bool {
+		self.patterns.is_empty() || self.patterns.contains(&pattern_no)
+	}
+
+	fn channel(&self, channel_no: usize) -> bool {
+		self.channels.is_empty() || self.channels.contains(&channel_no)
+	}
+
+	fn sample(&self, sample_number: u8) -> bool {
+		self.samples.is_empty() || self.samples.contains(&sample_number)
+	}
+}
+
+/// A note that would end up outside C-1 to B-3
+#[derive(Debug)]
+pub struct OutOfRange {
+	pub pattern: usize,
+	pub row: usize,
+	pub channel: usize,
+	pub period: u16,
+}
+
+/// Pattern cells hold finetune 0 periods, the replayer applies the
+/// finetune of the sample. C-1 to B-3.
+const PERIODS: &[u16; 36] = &FINETUNE_PERIODS[0];
+
+/// Finds the note in PERIODS, 0 is C-1 and 35 is B-3.
+/// Periods more than a semitone outside the table give None.
+fn find_note(period: u16) -> Option<usize> {
+	let period = period as i32;
+	if period > PERIODS[0] as i32 * 107 / 100 || period < PERIODS[35] as i32 * 94 / 100 {
+		return None;
+	}
+	(0..PERIODS.len()).min_by_key(|i| (period - PERIODS[*i] as i32).abs())
+}
+
+/// Moves period by semitones in PERIODS. Returns the new period and true,
+/// or, if the note ends up outside C-1 to B-3, the period clamped to
+/// C-1 or B-3 and false.
+pub fn transpose_period(period: u16, semitones: i32) -> (u16, bool) {
+	match find_note(period).map(|n| n as i32 + semitones) {
+		Some(note) if note >= 0 && note < PERIODS.len() as i32 => (PERIODS[note as usize], true),
+		Some(note) if note < 0 => (PERIODS[0], false),
+		Some(_) => (PERIODS[PERIODS.len() - 1], false),
+		None if period > PERIODS[0] => (PERIODS[0], false),
+		None => (PERIODS[PERIODS.len() - 1], false),
+	}
+}
+
+/// The sample played by each cell, following the play order
+#[derive(Debug)]
+pub struct SampleTracking {
+	/// Sample number for each pattern, row and channel, 0 if none
+	samples: Vec<Vec<Vec<u8>>>,
+	/// Patterns reached with different samples on a channel,
+	/// the samples from the first time it is played are used
+	pub ambiguous: Vec<usize>,
+}
+
+impl SampleTracking {
+	pub fn sample(&self, pattern: usize, row: usize, channel: usize) -> u8 {
+		self.samples.get(pattern)
+			.and_then(|p| p.get(row))
+			.and_then(|r| r.get(channel))
+			.cloned()
+			.unwrap_or(0)
+	}
+}
+
+/// Finds the sample played by each cell. Notes without a sample number use
+/// the last sample on the channel, also from earlier patterns in the play
+/// order. Patterns not in the play order start without samples.
+pub fn track_samples(module: &ptmf::PTModule) -> SampleTracking {
+	let num_channels = multichannel::num_channels(module);
+	let play = |pattern: &ptmf::Pattern, last_sample: &mut Vec<u8>| -> Vec<Vec<u8>> {
+		pattern.rows.iter().map(|row| {
+			last_sample.resize(row.channels.len(), 0);
+			for (channel_no, channel) in row.channels.iter().enumerate() {
+				if channel.sample_number > 0 {
+					last_sample[channel_no] = channel.sample_number;
+				}
+			}
+			last_sample.clone()
+		}).collect()
+	};
+
+	let mut samples: Vec<Option<Vec<Vec<u8>>>> = vec![None; module.patterns.len()];
+	let mut ambiguous = Vec::new();
+	let mut last_sample = vec![0u8; num_channels];
+	for position in 0..module.length as usize {
+		let pattern_no = module.positions.data[position] as usize;
+		let pattern = match module.patterns.get(pattern_no) {
+			Some(pattern) => pattern,
+			None => continue,
+		};
+		let played = play(pattern, &mut last_sample);
+		match samples[pattern_no] {
+			None => samples[pattern_no] = Some(played),
+			Some(ref first) => {
+				// Only notes without a sample number can differ
+				let differs = pattern.rows.iter().enumerate().any(|(row_no, row)| {
+					row.channels.iter().enumerate().any(|(channel_no, channel)| {
+						channel.period != 0 && first[row_no][channel_no] != played[row_no][channel_no]
+					})
+				});
+				if differs && !ambiguous.contains(&pattern_no) {
+					ambiguous.push(pattern_no);
+				}
+			},
+		}
+	}
+
+	let samples = samples.into_iter().zip(module.patterns.iter())
+		.map(|(played, pattern)| match played {
+			Some(played) => played,
+			None => play(pattern, &mut vec![0u8; num_channels]),
+		})
+		.collect();
+	ambiguous.sort();
+
+	SampleTracking{samples, ambiguous}
+}
+
+/// Transposes all selected notes by semitones. The period table is the same
+/// for all finetunes, the replayer applies the finetune of the sample.
+/// Notes that would fall outside C-1 to B-3 are clamped if clamp is true,
+/// otherwise they are left as they are. Returns the notes outside the range.
+pub fn transpose(module: &mut ptmf::PTModule, semitones: i32, selection: &Selection, clamp: bool) -> Vec<OutOfRange> {
+	let mut result = Vec::new();
+	let tracking = track_samples(module);
+
+	for (pattern_no, pattern) in module.patterns.iter_mut().enumerate() {
+		if !selection.pattern(pattern_no) {
+			continue;
+		}
+		for (row_no, row) in pattern.rows.iter_mut().enumerate() {
+			for (channel_no, channel) in row.channels.iter_mut().enumerate() {
+				let sample_number = tracking.sample(pattern_no, row_no, channel_no);
+				if channel.period == 0 || !selection.channel(channel_no) || !selection.sample(sample_number) {
+					continue;
+				}
+
+				let (period, in_range) = transpose_period(channel.period, semitones);
+				if !in_range {
+					result.push(OutOfRange{pattern: pattern_no, row: row_no, channel: channel_no, period: channel.period});
+				}
+				if in_range || clamp {
+					channel.period = period;
+				}
+			}
+		}
+	}
+
+	result
+}
+
+/// Rearranges the channels of the selected patterns, new channel i is
+/// old channel order[i]. order must be a permutation of the channels.
+pub fn reorder_channels(module: &mut ptmf::PTModule, order: &[usize], selection: &Selection) -> Result<()> {
+	let num_channels = multichannel::num_channels(module);
+	let mut sorted = order.to_vec();
+	sorted.sort();
+	if sorted != (0..num_channels).collect::<Vec<usize>>() {
+		return Err(anyhow!("Channel order must have each of the {} channels once", num_channels));
+	}
+
+	for (pattern_no, pattern) in module.patterns.iter_mut().enumerate() {
+		if !selection.pattern(pattern_no) {
+			continue;
+		}
+		for row in &mut pattern.rows {
+			row.channels = order.iter().map(|c| row.channels[*c].clone()).collect();
+		}
+	}
+
+	Ok(())
+}
+
+/// Swaps two channels in the selected patterns
+pub fn swap_channels(module: &mut ptmf::PTModule, a: usize, b: usize, selection: &Selection) -> Result<()> {
+	let num_channels = multichannel::num_channels(module);
+	if a >= num_channels || b >= num_channels {
+		return Err(anyhow!("Invalid channel, only {} channels", num_channels));
+	}
+
+	for (pattern_no, pattern) in module.patterns.iter_mut().enumerate() {
+		if !selection.pattern(pattern_no) {
+			continue;
+		}
+		for row in &mut pattern.rows {
+			row.channels.swap(a, b);
+		}
+	}
+
+	Ok(())
+}
+
+/// Clears notes and sample numbers of the selected channels and patterns,
+/// and also the effects unless keep_effects is true
+pub fn clear_channels(module: &mut ptmf::PTModule, selection: &Selection, keep_effects: bool) {
+	for (pattern_no, pattern) in module.patterns.iter_mut().enumerate() {
+		if !selection.pattern(pattern_no) {
+			continue;
+		}
+		for row in &mut pattern.rows {
+			for (channel_no, channel) in row.channels.iter_mut().enumerate() {
+				if !selection.channel(channel_no) {
+					continue;
+				}
+				channel.period = 0;
+				channel.sample_number = 0;
+				if !keep_effects {
+					channel.effect = 0;
+				}
+			}
+		}
+	}
+}
+
+/// Copies channel from in source to channel to in module, pattern by pattern,
+/// for the selected patterns that are in both modules
+pub fn copy_channel(module: &mut ptmf::PTModule, source: &ptmf::PTModule, from: usize, to: usize, selection: &Selection) -> Result<()> {
+	let num_channels = multichannel::num_channels(module);
+	let source_channels = multichannel::num_channels(source);
+	if to >= num_channels || from >= source_channels {
+		return Err(anyhow!("Invalid channel, only {} channels", num_channels.min(source_channels)));
+	}
+
+	for (pattern_no, pattern) in module.patterns.iter_mut().enumerate() {
+		if !selection.pattern(pattern_no) {
+			continue;
+		}
+		let source_pattern = match source.patterns.get(pattern_no) {
+			Some(pattern) => pattern,
+			None => continue,
+		};
+		for (row, source_row) in pattern.rows.iter_mut().zip(source_pattern.rows.iter()) {
+			row.channels[to] = source_row.channels[from].clone();
+		}
+	}
+
+	Ok(())
+}
+
+/// Removes patterns that are not in the play order and renumbers the
+/// play positions. Returns the removed pattern numbers.
+pub fn remove_unused_patterns(module: &mut ptmf::PTModule) -> Vec<u8> {
+	let unused = analysis::find_unused_patterns(module);
+
+	// MUST Remove highest pattern first
+	for i in unused.iter().rev() {
+		let i = *i;
+		// Remove pattern
+		module.patterns.remove(i as usize);
+
+		// Adjust play positions
+		for j in 0..module.length {
+			let j = j as usize;
+			if module.positions.data[j] > i {
+				module.positions.data[j] -= 1;
+			}
+		}
+	}
+
+	unused
+}
+
+/// Removes samples that are never used in a pattern by moving them last
+/// and clearing them, and renumbers the sample references.
+/// Returns the removed sample numbers.
+pub fn remove_unused_samples(module: &mut ptmf::PTModule) -> Vec<u8> {
+	let mut unused = analysis::find_unused_samples(module);
+	unused.sort();
+
+	// MUST remove highest sample first
+	for i in unused.iter().rev() {
+		let i = *i;
+		let index = i as usize - 1;
+
+		// Remove sample info and put it last
+		let mut si = module.sample_info.remove(index);
+
+		si.length = 0;
+		si.repeat_start = 0;
+		si.repeat_length = 0;
+		si.data.clear();
+		module.sample_info.push(si);
+
+		// Rewrite instrument references
+		// TODO optimize this
+		for pattern in &mut module.patterns {
+			for row in &mut pattern.rows {
+				for channel in &mut row.channels {
+					let number = channel.sample_number;
+					if number > i {
+						channel.sample_number -= 1;
+					}
+				}
+			}
+		}
+	}
+
+	unused
+}
+
+pub fn patterns_equal(a: &ptmf::Pattern, b: &ptmf::Pattern) -> bool {
+	if a.rows.len() != b.rows.len() {
+		return false;
+	}
+	for (row_a, row_b) in a.rows.iter().zip(b.rows.iter()) {
+		if row_a.channels.len() != row_b.channels.len() {
+			return false;
+		}
+		for (ca, cb) in row_a.channels.iter().zip(row_b.channels.iter()) {
+			if ca.period != cb.period ||
+				ca.sample_number != cb.sample_number ||
+				ca.effect != cb.effect {
+				return false;
+			}
+		}
+	}
+
+	true
+}
+
+/// Returns a list of duplicate pattern and the first identical pattern
+pub fn find_duplicate_patterns(module: &ptmf::PTModule) -> Vec<(usize, usize)> {
+	let mut duplicates: Vec<(usize, usize)> = Vec::new();
+	for i in 0..module.patterns.len() {
+		for j in 0..i {
+			if duplicates.iter().any(|(dup, _)| *dup == j) {
+				continue;
+			}
+			if patterns_equal(&module.patterns[i], &module.patterns[j]) {
+				duplicates.push((i, j));
+				break;
+			}
+		}
+	}
+
+	duplicates
+}
+
+/// Removes duplicate patterns and returns the number of bytes saved
+pub fn remove_duplicate_patterns(module: &mut ptmf::PTModule) -> usize {
+	let duplicates = find_duplicate_patterns(module);
+	let mut saved = 0;
+
+	// Point play positions at the first copy
+	for j in 0..module.length as usize {
+		let pattern = module.positions.data[j] as usize;
+		if let Some((_, first)) = duplicates.iter().find(|(dup, _)| *dup == pattern) {
+			module.positions.data[j] = *first as u8;
+		}
+	}
+
+	// MUST Remove highest pattern first
+	for (i, _) in duplicates.iter().rev() {
+		let i = *i;
+		let pattern = module.patterns.remove(i);
+		saved += pattern.rows.iter().map(|r| r.channels.len() * 4).sum::<usize>();
+
+		// Adjust play positions
+		for j in 0..module.length as usize {
+			if module.positions.data[j] as usize > i {
+				module.positions.data[j] -= 1;
+			}
+		}
+	}
+
+	saved
+}
+
+/// True if all notes using sample number can get a Cxx command instead
+/// of the sample volume, i.e. have no effect or already have Cxx
+pub fn can_bake_volume(module: &ptmf::PTModule, number: u8) -> bool {
+	for pattern in &module.patterns {
+		for row in &pattern.rows {
+			for channel in &row.channels {
+				if channel.sample_number == number &&
+					channel.effect != 0 &&
+					channel.effect & 0x0f00 != 0x0c00 {
+					return false;
+				}
+			}
+		}
+	}
+
+	true
+}
+
+/// Returns a list of duplicate sample number and the first identical sample number.
+/// Samples must have the same data, loop and finetune. Unless bake_volume is true
+/// they must also have the same volume.
+pub fn find_duplicate_samples(module: &ptmf::PTModule, bake_volume: bool) -> Vec<(u8, u8)> {
+	let mut duplicates: Vec<(u8, u8)> = Vec::new();
+	for i in 0..module.sample_info.len() {
+		let a = &module.sample_info[i];
+		if a.length == 0 {
+			continue;
+		}
+		for j in 0..i {
+			if duplicates.iter().any(|(dup, _)| *dup as usize == j + 1) {
+				continue;
+			}
+			let b = &module.sample_info[j];
+			if a.data != b.data ||
+				a.repeat_start != b.repeat_start ||
+				a.repeat_length != b.repeat_length ||
+				a.finetune != b.finetune {
+				continue;
+			}
+			if a.volume != b.volume &&
+				!(bake_volume && can_bake_volume(module, i as u8 + 1)) {
+				continue;
+			}
+			duplicates.push((i as u8 + 1, j as u8 + 1));
+			break;
+		}
+	}
+
+	duplicates
+}
+
+/// Points all references to duplicate samples at the first identical sample
+/// and clears the duplicates. With bake_volume the volume of a duplicate
+/// is set with Cxx where it differs. Returns the number of bytes saved.
+pub fn remove_duplicate_samples(module: &mut ptmf::PTModule, bake_volume: bool) -> usize {
+	let duplicates = find_duplicate_samples(module, bake_volume);
+	let mut saved = 0;
+
+	for (dup, first) in duplicates {
+		let volume = module.sample_info[dup as usize - 1].volume;
+		let bake = volume != module.sample_info[first as usize - 1].volume;
+
+		// Rewrite instrument references
+		for pattern in &mut module.patterns {
+			for row in &mut pattern.rows {
+				for channel in &mut row.channels {
+					if channel.sample_number != dup {
+						continue;
+					}
+					channel.sample_number = first;
+					if bake && channel.effect == 0 {
+						channel.effect = 0x0c00 | volume as u16;
+					}
+				}
+			}
+		}
+
+		let si = &mut module.sample_info[dup as usize - 1];
+		saved += si.data.len();
+		si.length = 0;
+		si.repeat_start = 0;
+		si.repeat_length = 0;
+		si.data.clear();
+	}
+
+	saved
+}
+
+/// Appends the patterns and play positions of other to module.
+/// With sync_only all notes are dropped and only E8x, Fxx, Dxx and Bxx
+/// commands are kept. The samples of other are not used.
+pub fn append(module: &mut ptmf::PTModule, other: &ptmf::PTModule, sync_only: bool) -> Result<()> {
+	let num_channels = multichannel::num_channels(module);
+	if multichannel::num_channels(other) != num_channels {
+		return Err(anyhow!("Unable to merge a module with {} channels into a module with {} channels",
+			multichannel::num_channels(other), num_channels));
+	}
+	if module.patterns.len() + other.patterns.len() > 128 {
+		return Err(anyhow!("Too many patterns, {} and {}", module.patterns.len(), other.patterns.len()));
+	}
+	if module.length as usize + other.length as usize > 128 {
+		return Err(anyhow!("Song too long, {} and {} positions", module.length, other.length));
+	}
+
+	let new_offset = module.patterns.len() as u8;
+
+	for pattern in &other.patterns {
+		let mut pattern = pattern.clone();
+		if sync_only {
+			for row in &mut pattern.rows {
+				for channel in &mut row.channels {
+					channel.period = 0;
+					channel.sample_number = 0;
+					let keep = channel.effect & 0x0ff0 == 0x0e80 ||
+						channel.effect & 0x0f00 == 0x0f00 ||
+						channel.effect & 0x0d00 == 0x0d00 ||
+						channel.effect & 0x0b00 == 0x0b00;
+					if !keep {
+						channel.effect = 0;
+					}
+				}
+			}
+		}
+		module.patterns.push(pattern)
+	}
+
+	for i in 0..other.length as usize {
+		module.positions.data[module.length as usize] = other.positions.data[i] + new_offset;
+		module.length += 1;
+	}
+
+	Ok(())
+}
+
+/// Result of inserting an E81 sync command in a pattern
+#[derive(Debug, PartialEq)]
+pub enum SyncInsert {
+	/// The pattern already has an E8x command
+	HasSync,
+	/// E81 was inserted at row and channel
+	Inserted{row: usize, channel: usize},
+	/// There is no cell without an effect
+	NoRoom,
+}
+
+/// Inserts E81 in the first cell without an effect of each pattern
+/// that has no E8x command. Returns the result for each pattern.
+pub fn insert_sync(module: &mut ptmf::PTModule) -> Vec<SyncInsert> {
+	let mut result = Vec::new();
+	for pattern in &mut module.patterns {
+
+		// Skip if pattern has e8 command
+		let has_e8 = pattern.rows.iter()
+			.flat_map(|row| row.channels.iter())
+			.any(|channel| channel.effect & 0x0ff0 == 0x0e80);
+		if has_e8 {
+			result.push(SyncInsert::HasSync);
+			continue;
+		}
+
+		// insert e8 command
+		let mut inserted = SyncInsert::NoRoom;
+		'rows: for (row_no, row) in pattern.rows.iter_mut().enumerate() {
+			for (channel_no, channel) in row.channels.iter_mut().enumerate() {
+				if channel.effect == 0x0 {
+					channel.effect = 0x0e81;
+					inserted = SyncInsert::Inserted{row: row_no, channel: channel_no};
+					break 'rows;
+				}
+			}
+		}
+		result.push(inserted);
+	}
+
+	result
+}
+
+/// A sample that was shortened by trim_samples, lengths in bytes
+#[derive(Debug)]
+pub struct TrimmedSample {
+	/// Sample number 1-31
+	pub number: usize,
+	pub old_length: usize,
+	pub new_length: usize,
+}
+
+/// Removes sample data that is never played. Looped samples are cut at
+/// the loop end and one-shot samples lose their silent tail, see
+/// analysis::SILENCE_LEVEL. The length stays even and the first two
+/// bytes, which ProTracker plays as the idle loop of one-shot samples,
+/// are always kept. Returns the samples that were shortened.
+pub fn trim_samples(module: &mut ptmf::PTModule) -> Vec<TrimmedSample> {
+	let mut result = Vec::new();
+	for (i, si) in module.sample_info.iter_mut().enumerate() {
+		let old_length = si.data.len();
+		if si.length == 0 || old_length <= 2 {
+			continue;
+		}
+
+		let mut new_length = if si.repeat_length > 1 {
+			let loop_end = (si.repeat_start as usize + si.repeat_length as usize) * 2;
+			cmp::min(loop_end, old_length)
+		} else {
+			old_length - analysis::trailing_silence(&si.data, analysis::SILENCE_LEVEL)
+		};
+		new_length = cmp::max(new_length + (new_length & 1), 2);
+
+		if new_length >= old_length {
+			continue;
+		}
+
+		si.data.truncate(new_length);
+		si.length = (new_length / 2) as u16;
+		result.push(TrimmedSample{number: i + 1, old_length, new_length});
+	}
+
+	result
+}
+
+/// A 9xx sample offset that does not fit in the effect after resampling
+#[derive(Debug)]
+pub struct OffsetOverflow {
+	pub pattern: usize,
+	pub row: usize,
+	pub channel: usize,
+	/// The resampled offset in units of 256 bytes
+	pub offset: u32,
+}
+
+/// A portamento speed that could not be scaled exactly when resampling
+#[derive(Debug)]
+pub struct SlideClamp {
+	pub pattern: usize,
+	pub row: usize,
+	pub channel: usize,
+	/// The original effect
+	pub effect: u16,
+	/// The effect written instead
+	pub new_effect: u16,
+}
+
+/// Notes, sample offsets and portamentos that were clamped when resampling
+#[derive(Debug, Default)]
+pub struct ResampleWarnings {
+	/// Notes that ended up outside C-1 to B-3, with the old period
+	pub out_of_range: Vec<OutOfRange>,
+	/// 9xx offsets above $ff
+	pub offsets: Vec<OffsetOverflow>,
+	/// 1xx, 2xx, 3xx, E1x and E2x speeds that were clamped
+	pub slides: Vec<SlideClamp>,
+	/// True if the first two bytes of a one-shot sample were set to zero
+	pub zeroed_start: bool,
+}
+
+/// The number of semitones the notes move when a sample is resampled by
+/// factor. Only factors that are semitone ratios, like 2, 0.5 or 1.0595,
+/// keep the pitch in the period table.
+pub fn resample_semitones(factor: f64) -> Result<i32> {
+	if factor <= 0.0 || !factor.is_finite() {
+		return Err(anyhow!("Invalid resample factor {}", factor));
+	}
+	let semitones = 12.0 * factor.log2();
+	if (semitones - semitones.round()).abs() > 0.01 {
+		return Err(anyhow!("Resample factor {} is not a semitone ratio, {:.2} semitones", factor, semitones));
+	}
+
+	Ok(semitones.round() as i32)
+}
+
+/// Scales a 9xx sample offset by factor. Returns the new offset and true,
+/// or $ff and false if it does not fit.
+pub fn resample_offset(offset: u8, factor: f64) -> (u8, bool) {
+	let offset = (offset as f64 * factor).round() as u32;
+	if offset > 0xff {
+		(0xff, false)
+	} else {
+		(offset as u8, true)
+	}
+}
+
+/// Scales the speed of a portamento by factor. Periods are divided by
+/// the factor, so the speed in period units per tick is too. Returns the
+/// new effect and true, or the nearest speed that fits and false.
+/// Other effects are returned unchanged.
+pub fn resample_slide(effect: u16, factor: f64) -> (u16, bool) {
+	let (command, mask) = match effect & 0x0ff0 {
+		0x0e10 | 0x0e20 => (effect & 0x0ff0, 0x0f),
+		_ => match effect & 0x0f00 {
+			0x0100 | 0x0200 | 0x0300 => (effect & 0x0f00, 0xff),
+			_ => return (effect, true),
+		},
+	};
+	let speed = effect & mask;
+	if speed == 0 {
+		// 300 keeps the last speed and 100, 200 do nothing
+		return (effect, true);
+	}
+	let scaled = (speed as f64 / factor).round() as u32;
+	let new_speed = scaled.max(1).min(mask as u32) as u16;
+	(command | new_speed, new_speed as u32 == scaled)
+}
+
+/// Resamples sample number by factor, the new length divided by the old,
+/// so 0.5 halves the sample rate. factor must be a semitone ratio. The loop
+/// is scaled, the notes played with the sample are transposed in the
+/// finetune 0 table to keep the pitch, 9xx offsets are scaled and so are
+/// portamento speeds, which are in periods. Notes without a sample number
+/// use the last sample on the channel in the play order, like transpose.
+/// Notes outside C-1 to B-3, offsets above $ff and portamentos that do
+/// not fit are clamped and returned. If zero_start is set, the first two
+/// bytes of a one-shot sample are set to zero, as ProTracker plays them
+/// repeatedly after the end.
+pub fn resample_sample(module: &mut ptmf::PTModule, number: u8, factor: f64, zero_start: bool) -> Result<ResampleWarnings> {
+	if number == 0 || number as usize > module.sample_info.len() {
+		return Err(anyhow!("Invalid sample number {}", number));
+	}
+	let semitones = resample_semitones(factor)?;
+	// The exact ratio, so the sample matches the new periods
+	let factor = 2f64.powf(semitones as f64 / 12.0);
+
+	let si = &mut module.sample_info[number as usize - 1];
+	if si.length == 0 {
+		return Err(anyhow!("Sample {} is empty", number));
+	}
+
+	// Only the ratio between the rates matters
+	const RATE: f64 = 1_000_000.0;
+	let mut resampled = sample::from_raw(&si.data);
+	resampled.sample_rate = Some(RATE as u32);
+	resampled.volume = Some(si.volume);
+	resampled.finetune = Some(note::finetune_to_signed(si.finetune));
+	if si.repeat_length > 1 {
+		let start = si.repeat_start as usize * 2;
+		resampled.sample_loop = Some((start, start + si.repeat_length as usize * 2));
+	}
+	resampled.resample((RATE * factor).round() as u32)?;
+	resampled.apply(si)?;
+
+	let mut result = ResampleWarnings::default();
+	if zero_start && si.repeat_length <= 1 && si.data.iter().take(2).any(|b| *b != 0) {
+		for b in si.data.iter_mut().take(2) {
+			*b = 0;
+		}
+		result.zeroed_start = true;
+	}
+
+	let tracking = track_samples(module);
+	for (pattern_no, pattern) in module.patterns.iter_mut().enumerate() {
+		for (row_no, row) in pattern.rows.iter_mut().enumerate() {
+			for (channel_no, channel) in row.channels.iter_mut().enumerate() {
+				if tracking.sample(pattern_no, row_no, channel_no) != number {
+					continue;
+				}
+
+				if channel.period != 0 {
+					let (period, in_range) = transpose_period(channel.period, semitones);
+					if !in_range {
+						result.out_of_range.push(OutOfRange{pattern: pattern_no, row: row_no, channel: channel_no, period: channel.period});
+					}
+					channel.period = period;
+				}
+
+				if channel.effect & 0x0f00 == 0x0900 {
+					let (offset, fits) = resample_offset(channel.effect as u8, factor);
+					if !fits {
+						let offset = ((channel.effect & 0xff) as f64 * factor).round() as u32;
+						result.offsets.push(OffsetOverflow{pattern: pattern_no, row: row_no, channel: channel_no, offset});
+					}
+					channel.effect = 0x0900 | offset as u16;
+				}
+
+				let (effect, exact) = resample_slide(channel.effect, factor);
+				if !exact {
+					result.slides.push(SlideClamp{pattern: pattern_no, row: row_no, channel: channel_no, effect: channel.effect, new_effect: effect});
+				}
+				channel.effect = effect;
+			}
+		}
+	}
+
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn transpose_period_moves_by_semitones() {
+		assert_eq!(transpose_period(428, 0), (428, true));
+		assert_eq!(transpose_period(428, 1), (404, true));
+		assert_eq!(transpose_period(428, 12), (214, true));
+		assert_eq!(transpose_period(428, -12), (856, true));
+		assert_eq!(transpose_period(856, 35), (113, true));
+	}
+
+	#[test]
+	fn transpose_period_uses_finetune_0_table() {
+		// C-1 + 1 semitone is C#-1 whatever the finetune of the sample
+		assert_eq!(transpose_period(856, 1), (808, true));
+		// Periods between notes snap to the nearest note
+		assert_eq!(transpose_period(430, 1), (404, true));
+	}
+
+	#[test]
+	fn transpose_period_clamps_out_of_range() {
+		assert_eq!(transpose_period(856, -1), (856, false));
+		assert_eq!(transpose_period(113, 1), (113, false));
+		assert_eq!(transpose_period(428, 25), (113, false));
+		assert_eq!(transpose_period(428, -13), (856, false));
+		// Periods outside the table
+		assert_eq!(transpose_period(1712, 0), (856, false));
+		assert_eq!(transpose_period(57, 0), (113, false));
+	}
+
+	#[test]
+	fn resample_semitones_from_factor() {
+		assert_eq!(resample_semitones(1.0).unwrap(), 0);
+		assert_eq!(resample_semitones(2.0).unwrap(), 12);
+		assert_eq!(resample_semitones(0.5).unwrap(), -12);
+		assert_eq!(resample_semitones(1.0595).unwrap(), 1);
+		assert_eq!(resample_semitones(0.7937).unwrap(), -4);
+		assert!(resample_semitones(1.5).is_err());
+		assert!(resample_semitones(0.0).is_err());
+		assert!(resample_semitones(-2.0).is_err());
+	}
+
+	#[test]
+	fn resample_maps_periods_in_finetune_0_table() {
+		// Half the rate doubles the period, C-3 to C-2
+		let semitones = resample_semitones(0.5).unwrap();
+		assert_eq!(transpose_period(214, semitones), (428, true));
+		// Twice the rate, D-1 to D-2, not 762 / 2 = 381
+		let semitones = resample_semitones(2.0).unwrap();
+		assert_eq!(transpose_period(762, semitones), (381, true));
+		assert_eq!(transpose_period(678, semitones), (339, true));
+		// Notes that end up above B-3 are clamped
+		assert_eq!(transpose_period(214, semitones), (113, false));
+	}
+
+	#[test]
+	fn resample_offset_scales_and_clamps() {
+		assert_eq!(resample_offset(0x10, 0.5), (0x08, true));
+		assert_eq!(resample_offset(0x10, 2.0), (0x20, true));
+		assert_eq!(resample_offset(0x80, 2.0), (0xff, false));
+		assert_eq!(resample_offset(0, 2.0), (0, true));
+	}
+
+	#[test]
+	fn resample_slide_scales_portamento_speeds() {
+		// Twice the rate halves the periods and the speeds
+		assert_eq!(resample_slide(0x0110, 2.0), (0x0108, true));
+		assert_eq!(resample_slide(0x0220, 2.0), (0x0210, true));
+		assert_eq!(resample_slide(0x0308, 0.5), (0x0310, true));
+		assert_eq!(resample_slide(0x0e14, 2.0), (0x0e12, true));
+		assert_eq!(resample_slide(0x0e24, 0.5), (0x0e28, true));
+		// Speeds that do not fit are clamped
+		assert_eq!(resample_slide(0x0390, 0.5), (0x03ff, false));
+		assert_eq!(resample_slide(0x0e2c, 0.5), (0x0e2f, false));
+		assert_eq!(resample_slide(0x0101, 4.0), (0x0101, false));
+		// 300 keeps the last speed, other effects are unchanged
+		assert_eq!(resample_slide(0x0300, 2.0), (0x0300, true));
+		assert_eq!(resample_slide(0x0a10, 2.0), (0x0a10, true));
+		assert_eq!(resample_slide(0x0e30, 2.0), (0x0e30, true));
+	}
+}
@@ -0,0 +1,32 @@
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::analysis;
+
+/// Computes The Player usecode for a list of effect indices
+/// in ptmf::EFFECT_NAMES and whether finetune is used
+pub fn from_effects(effects: &[usize], finetune: bool) -> u32 {
+	let mut usecode:u32 = 0;
+	for i in effects {
+		// Some have special handling
+		if *i == 0 {
+			usecode |= 1 << 8; // The player converts 0 to 8
+		} else {
+			usecode |= 1 << i;
+		}
+	}
+
+	if finetune {
+		usecode |= 1;
+	}
+
+	usecode
+}
+
+/// Computes The Player usecode for the effects used in the module
+pub fn usecode(module: &ptmf::PTModule) -> u32 {
+	let effects = analysis::used_effects(module);
+	let finetune = module.sample_info.iter().any(|si| si.finetune != 0);
+
+	from_effects(&effects, finetune)
+}
@@ -0,0 +1,360 @@
+use std::io::{self, Seek, SeekFrom, Write};
+use anyhow::{Result, anyhow};
+
+// ProTracker and ThePlayer
+use modfile::ptmf;
+
+use crate::note;
+use crate::sample::{self, Sample};
+
+pub use crate::chunk::Chunk;
+use crate::chunk::{self, ByteOrder};
+
+fn push_u16(data: &mut Vec<u8>, val: u16) {
+	data.extend_from_slice(&val.to_le_bytes());
+}
+
+fn push_u32(data: &mut Vec<u8>, val: u32) {
+	data.extend_from_slice(&val.to_le_bytes());
+}
+
+/// Writes a RIFF WAVE file containing chunks
+pub fn write_riff(writer: &mut dyn Write, chunks: &[Chunk]) -> io::Result<()> {
+	chunk::write_container(writer, ByteOrder::Little, b"RIFF", b"WAVE", chunks)
+}
+
+/// Creates a PCM fmt chunk
+pub fn fmt_chunk(channels: u16, sample_rate: u32, bits_per_sample: u16) -> Chunk {
+	let block_align = channels * bits_per_sample / 8;
+	let mut data = Vec::new();
+	push_u16(&mut data, 1); // PCM
+	push_u16(&mut data, channels);
+	push_u32(&mut data, sample_rate);
+	push_u32(&mut data, sample_rate * block_align as u32);
+	push_u16(&mut data, block_align);
+	push_u16(&mut data, bits_per_sample);
+	Chunk::new(b"fmt ", data)
+}
+
+/// Creates a smpl chunk with at most one forward loop.
+/// loop_end is inclusive.
+fn smpl_chunk(sample_rate: u32, unity_note: u8, sample_loop: Option<(u32, u32)>) -> Chunk {
+	let mut data = Vec::new();
+	push_u32(&mut data, 0); // Manufacturer
+	push_u32(&mut data, 0); // Product
+	push_u32(&mut data, 1_000_000_000 / sample_rate.max(1)); // Sample period in ns
+	push_u32(&mut data, unity_note as u32);
+	push_u32(&mut data, 0); // Pitch fraction
+	push_u32(&mut data, 0); // SMPTE format
+	push_u32(&mut data, 0); // SMPTE offset
+	push_u32(&mut data, if sample_loop.is_some() { 1 } else { 0 });
+	push_u32(&mut data, 0); // Sampler data
+	if let Some((loop_start, loop_end)) = sample_loop {
+		push_u32(&mut data, 0); // Cue point id
+		push_u32(&mut data, 0); // Forward loop
+		push_u32(&mut data, loop_start);
+		push_u32(&mut data, loop_end);
+		push_u32(&mut data, 0); // Fraction
+		push_u32(&mut data, 0); // Play count, infinite
+	}
+	Chunk::new(b"smpl", data)
+}
+
+/// Creates an inst chunk with finetune in cents and volume as gain in dB.
+/// The chunk only allows -50 to 50 cents, the exact finetune is in the
+/// comment written by write_sample.
+fn inst_chunk(unity_note: u8, finetune: u8, volume: u8) -> Chunk {
+	// One finetune step is 1/8 of a semitone
+	let cents = (note::finetune_to_signed(finetune) as f64 * 12.5).round().clamp(-50.0, 50.0) as i8;
+	let gain = if volume == 0 {
+		-64
+	} else {
+		(20.0 * (volume.min(64) as f64 / 64.0).log10()).round() as i8
+	};
+	let data = vec![unity_note, cents as u8, gain as u8, 0, 127, 1, 127];
+	Chunk::new(b"inst", data)
+}
+
+/// Creates a LIST INFO chunk with name and comment
+fn info_chunk(name: &str, comment: &str) -> Chunk {
+	let mut data = Vec::new();
+	data.extend_from_slice(b"INFO");
+	for (id, text) in [(b"INAM", name), (b"ICMT", comment)].iter() {
+		if text.is_empty() {
+			continue;
+		}
+		let mut text = text.as_bytes().to_vec();
+		text.push(0);
+		data.extend_from_slice(*id);
+		push_u32(&mut data, text.len() as u32);
+		data.extend_from_slice(&text);
+		if text.len() & 1 == 1 {
+			data.push(0);
+		}
+	}
+	Chunk::new(b"LIST", data)
+}
+
+/// Returns the loop of a sample in bytes, start and inclusive end,
+/// or None if the sample does not loop
+pub fn sample_loop(sample: &ptmf::SampleInfo) -> Option<(u32, u32)> {
+	if sample.repeat_length <= 1 {
+		return None;
+	}
+	let start = sample.repeat_start as u32 * 2;
+	let end = (start + sample.repeat_length as u32 * 2).min(sample.data.len() as u32);
+	if start >= end {
+		return None;
+	}
+	Some((start, end - 1))
+}
+
+/// Writes a sample as 8-bit unsigned mono WAV.
+/// Loop points are stored in a smpl chunk, finetune and volume
+/// in an inst chunk and the sample name in a LIST INFO chunk.
+/// unity_note is the MIDI note played at sample_rate.
+pub fn write_sample(writer: &mut dyn Write, sample: &ptmf::SampleInfo, sample_rate: u32, unity_note: u8) -> io::Result<()> {
+	// Signed to unsigned
+	let data: Vec<u8> = sample.data.iter().map(|b| b ^ 0x80).collect();
+	let comment = format!("Finetune: {} Volume: {}", note::finetune_to_signed(sample.finetune), sample.volume);
+
+	let chunks = [
+		fmt_chunk(1, sample_rate, 8),
+		Chunk::new(b"data", data),
+		smpl_chunk(sample_rate, unity_note, sample_loop(sample)),
+		inst_chunk(unity_note, sample.finetune, sample.volume),
+		info_chunk(&sample.name, &comment),
+	];
+
+	write_riff(writer, &chunks)
+}
+
+/// Writes interleaved 16-bit signed samples as WAV
+pub fn write_pcm16(writer: &mut dyn Write, channels: u16, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+	let mut data = Vec::with_capacity(samples.len() * 2);
+	for sample in samples {
+		push_u16(&mut data, *sample as u16);
+	}
+
+	let chunks = [
+		fmt_chunk(channels, sample_rate, 16),
+		Chunk::new(b"data", data),
+	];
+
+	write_riff(writer, &chunks)
+}
+
+/// Offsets of the RIFF size and the data chunk size in the header
+/// written by Pcm16Writer, the fmt chunk is 16 bytes
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 12 + 8 + 16 + 4;
+
+/// Writes interleaved 16-bit signed samples as WAV while they are
+/// produced. The sizes in the header are written by finish.
+pub struct Pcm16Writer<W: Write + Seek> {
+	writer: W,
+	data_size: u32,
+}
+
+impl<W: Write + Seek> Pcm16Writer<W> {
+	/// Writes the header of an empty file
+	pub fn new(mut writer: W, channels: u16, sample_rate: u32) -> io::Result<Pcm16Writer<W>> {
+		let chunks = [
+			fmt_chunk(channels, sample_rate, 16),
+			Chunk::new(b"data", Vec::new()),
+		];
+		write_riff(&mut writer, &chunks)?;
+		Ok(Pcm16Writer{writer, data_size: 0})
+	}
+
+	pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+		self.data_size = (samples.len() as u32).checked_mul(2)
+			.and_then(|size| size.checked_add(self.data_size))
+			.filter(|size| *size <= u32::MAX - DATA_SIZE_OFFSET as u32)
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "WAV file is larger than 4 GB"))?;
+		let mut data = Vec::with_capacity(samples.len() * 2);
+		for sample in samples {
+			push_u16(&mut data, *sample as u16);
+		}
+		self.writer.write_all(&data)
+	}
+
+	/// Writes the sizes to the header and returns the writer
+	pub fn finish(mut self) -> io::Result<W> {
+		// The file size after the RIFF size
+		let riff_size = (DATA_SIZE_OFFSET - RIFF_SIZE_OFFSET) as u32 + self.data_size;
+		self.writer.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+		self.writer.write_all(&riff_size.to_le_bytes())?;
+		self.writer.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+		self.writer.write_all(&self.data_size.to_le_bytes())?;
+		self.writer.seek(SeekFrom::End(0))?;
+		self.writer.flush()?;
+		Ok(self.writer)
+	}
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+	ByteOrder::Little.read_u16(buf, offset)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+	ByteOrder::Little.read_u32(buf, offset)
+}
+
+/// Splits a RIFF WAVE file into chunks
+pub fn read_riff(buf: &[u8]) -> Result<Vec<Chunk>> {
+	chunk::read_container(buf, ByteOrder::Little, b"RIFF", b"WAVE")
+}
+
+/// Converts interleaved PCM or float data to mono -1.0 to 1.0
+fn decode_pcm(data: &[u8], format: u16, channels: u16, bits_per_sample: u16) -> Result<Vec<f32>> {
+	let bytes = (bits_per_sample as usize).div_ceil(8);
+	let frame_size = bytes * channels as usize;
+	if frame_size == 0 {
+		return Err(anyhow!("Invalid fmt chunk"));
+	}
+
+	let decode = |s: &[u8]| -> Result<f32> {
+		Ok(match (format, bytes) {
+			(1, 1) => (s[0] as f32 - 128.0) / 128.0,
+			(1, 2) => i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0,
+			(1, 3) => (i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) as f32 / 8388608.0,
+			(1, 4) => i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2147483648.0,
+			(3, 4) => f32::from_le_bytes([s[0], s[1], s[2], s[3]]),
+			(3, 8) => f64::from_le_bytes([s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]]) as f32,
+			_ => return Err(anyhow!("Unsupported WAV format {} with {} bits per sample", format, bits_per_sample)),
+		})
+	};
+
+	let mut result = Vec::with_capacity(data.len() / frame_size);
+	for frame in data.chunks_exact(frame_size) {
+		// Downmix
+		let mut sum = 0.0;
+		for s in frame.chunks_exact(bytes) {
+			sum += decode(s)?;
+		}
+		result.push(sum / channels as f32);
+	}
+
+	Ok(result)
+}
+
+/// Reads a WAV file of any PCM bit depth, or float, and any number of channels.
+/// Loop points are read from the smpl chunk, finetune and volume from
+/// the inst chunk or from the comment written by write_sample, and
+/// the name from the LIST INFO chunk.
+pub fn read_sample(buf: &[u8]) -> Result<Sample> {
+	let chunks = read_riff(buf)?;
+	let mut result = Sample::default();
+	let mut fmt = None;
+	let mut comment = None;
+
+	for chunk in &chunks {
+		let data = &chunk.data;
+		match &chunk.id {
+			b"fmt " if data.len() >= 16 => {
+				let mut format = read_u16(data, 0);
+				if format == 0xfffe && data.len() >= 26 {
+					// WAVE_FORMAT_EXTENSIBLE, the sub format starts with the format tag
+					format = read_u16(data, 24);
+				}
+				fmt = Some((format, read_u16(data, 2), read_u16(data, 14)));
+				let sample_rate = read_u32(data, 4);
+				if sample_rate == 0 {
+					return Err(anyhow!("Invalid sample rate 0 Hz"));
+				}
+				result.sample_rate = Some(sample_rate);
+			},
+			b"smpl" if data.len() >= 36 => {
+				let num_loops = read_u32(data, 28);
+				if num_loops > 0 && data.len() >= 36 + 24 {
+					let start = read_u32(data, 36 + 8) as usize;
+					let end = read_u32(data, 36 + 12) as usize + 1;
+					result.sample_loop = Some((start, end));
+				}
+			},
+			b"inst" if data.len() >= 3 => {
+				let cents = data[1] as i8 as f64;
+				let gain = data[2] as i8 as f64;
+				result.finetune = Some((cents / 12.5).round().clamp(-8.0, 7.0) as i8);
+				result.volume = Some((64.0 * 10f64.powf(gain / 20.0)).round().min(64.0) as u8);
+			},
+			b"LIST" if data.len() >= 4 && &data[0..4] == b"INFO" => {
+				let mut offset = 4;
+				while offset + 8 <= data.len() {
+					let size = read_u32(data, offset + 4) as usize;
+					let start = offset + 8;
+					let end = (start + size).min(data.len());
+					let text = String::from_utf8_lossy(&data[start..end])
+						.trim_end_matches('\0').to_string();
+					match &data[offset..offset + 4] {
+						b"INAM" => result.name = Some(text),
+						b"ICMT" => comment = Some(text),
+						_ => (),
+					}
+					offset = start + size + (size & 1);
+				}
+			},
+			_ => (),
+		}
+	}
+
+	let (format, channels, bits_per_sample) = match fmt {
+		Some(fmt) => fmt,
+		None => return Err(anyhow!("WAV file has no fmt chunk")),
+	};
+	let data = match chunks.iter().find(|c| &c.id == b"data") {
+		Some(chunk) => &chunk.data,
+		None => return Err(anyhow!("WAV file has no data chunk")),
+	};
+	result.data = decode_pcm(data, format, channels, bits_per_sample)?;
+
+	if let Some(comment) = comment {
+		sample::parse_comment(&comment, &mut result);
+	}
+
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil;
+
+	#[test]
+	fn inst_finetune_is_clamped_to_50_cents() {
+		assert_eq!(inst_chunk(60, 8, 64).data[1] as i8, -50);
+		assert_eq!(inst_chunk(60, 7, 64).data[1] as i8, 50);
+		assert_eq!(inst_chunk(60, 1, 64).data[1] as i8, 13);
+		assert_eq!(inst_chunk(60, 0, 64).data[1] as i8, 0);
+	}
+
+	#[test]
+	fn pcm16_writer_matches_write_pcm16() {
+		let samples: Vec<i16> = (0..1001).map(|i| (i * 37 - 16000) as i16).collect();
+		let mut expected = Vec::new();
+		write_pcm16(&mut expected, 2, 22050, &samples).unwrap();
+
+		let mut writer = Pcm16Writer::new(io::Cursor::new(Vec::new()), 2, 22050).unwrap();
+		for part in samples.chunks(100) {
+			writer.write(part).unwrap();
+		}
+		let written = writer.finish().unwrap().into_inner();
+		assert_eq!(written, expected);
+	}
+
+	#[test]
+	fn finetune_round_trip() {
+		let mut module = testutil::module(1);
+		testutil::set_square(&mut module, 1, 8);
+		for finetune in 0..16 {
+			let si = &mut module.sample_info[0];
+			si.finetune = finetune;
+			let mut buf = Vec::new();
+			write_sample(&mut buf, si, 8363, 60).unwrap();
+			let sample = read_sample(&buf).unwrap();
+			assert_eq!(sample.finetune, Some(note::finetune_to_signed(finetune)));
+			assert_eq!(sample.sample_loop, Some((0, 16)));
+		}
+	}
+}